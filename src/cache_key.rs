@@ -0,0 +1,398 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::Instant;
+
+use globset::GlobBuilder;
+use sha2::Digest;
+use sha2::Sha256;
+
+/// An entry of a command's `cacheKeyFiles`.
+pub struct CacheKeyFileEntry {
+  /// File path, directory path, or glob relative to the command's cwd.
+  pub path: String,
+  /// Whether it's ok for the path to not exist or the glob to match nothing.
+  pub optional: bool,
+}
+
+/// Hashes the contents of the files the entries refer to. Directories and
+/// globs are expanded to the files they contain in a deterministic order and
+/// their paths are hashed relative to the directory they were expanded from.
+pub fn hash_cache_key_files(cwd: &Path, entries: &[CacheKeyFileEntry]) -> Result<String, String> {
+  let mut hasher = Sha256::new();
+  for entry in entries {
+    if is_glob(&entry.path) {
+      let (base_dir, files) = expand_glob(cwd, &entry.path)?;
+      if files.is_empty() && !entry.optional {
+        return Err(format!("No files matched the glob '{}'.", entry.path));
+      }
+      hash_files(&mut hasher, &base_dir, &files)?;
+      continue;
+    }
+
+    let path = cwd.join(&entry.path);
+    match std::fs::metadata(&path) {
+      Ok(metadata) if metadata.is_dir() => {
+        let mut files = Vec::new();
+        collect_files(&path, &mut files)?;
+        hash_files(&mut hasher, &path, &files)?;
+      }
+      // only the contents are hashed for individual files (not the path)
+      // in order to keep the cache key the same as in previous versions
+      Ok(_) => hasher.update(read_file(&path)?),
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound && entry.optional => {
+        // ensure the cache key changes once the file is created
+        hasher.update(b"\0missing\0");
+        hasher.update(entry.path.as_bytes());
+      }
+      Err(err) => {
+        return Err(format!(
+          "Unable to read file '{}': {}.",
+          path.display(),
+          err
+        ));
+      }
+    }
+  }
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// How long a command's `cacheKeyCommand` may run before it's killed.
+pub const CACHE_KEY_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs the command with the formatting command's environment variables and
+/// hashes what it writes to stdout (ex. the output of `black --version`).
+pub fn hash_cache_key_command(
+  cwd: &Path,
+  executable: &str,
+  args: &[String],
+  env: &BTreeMap<String, String>,
+  timeout: Duration,
+) -> Result<String, String> {
+  let mut child = Command::new(executable)
+    .current_dir(cwd)
+    .args(args)
+    .envs(env)
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .map_err(|err| format!("Unable to run '{}': {}.", executable, err))?;
+
+  // read the output on other threads so the child never blocks on a full pipe
+  let stdout = read_to_end_in_thread(child.stdout.take().unwrap());
+  let stderr = read_to_end_in_thread(child.stderr.take().unwrap());
+  let start = Instant::now();
+  let status = loop {
+    match child.try_wait() {
+      Ok(Some(status)) => break status,
+      Ok(None) if start.elapsed() >= timeout => {
+        let _ignore = child.kill();
+        let _ignore = child.wait();
+        return Err(format!(
+          "Running '{}' exceeded the timeout of {}s.",
+          executable,
+          timeout.as_secs_f64()
+        ));
+      }
+      Ok(None) => std::thread::sleep(Duration::from_millis(10)),
+      Err(err) => return Err(format!("Unable to run '{}': {}.", executable, err)),
+    }
+  };
+
+  let stdout = stdout.join().unwrap_or_default();
+  if !status.success() {
+    let stderr = stderr.join().unwrap_or_default();
+    return Err(format!(
+      "'{}' exited with {}: {}",
+      executable,
+      status,
+      String::from_utf8_lossy(&stderr).trim()
+    ));
+  }
+  Ok(format!("{:x}", Sha256::digest(stdout)))
+}
+
+/// Hashes the resolved path, size, and modified time of the executable so
+/// that switching to a different version of it changes the hash.
+pub fn hash_cache_key_executable(cwd: &Path, executable: &str) -> Result<String, String> {
+  let path = resolve_executable(cwd, executable)
+    .ok_or_else(|| format!("Unable to find executable '{}' on the PATH.", executable))?;
+  // follow symlinks (ex. from version managers) to the actual file
+  let path = path.canonicalize().unwrap_or(path);
+  let metadata = std::fs::metadata(&path)
+    .map_err(|err| format!("Unable to read executable '{}': {}.", path.display(), err))?;
+  let modified = metadata
+    .modified()
+    .ok()
+    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+    .unwrap_or_default();
+  let mut hasher = Sha256::new();
+  hasher.update(path.to_string_lossy().as_bytes());
+  hasher.update(b"\0");
+  hasher.update(metadata.len().to_le_bytes());
+  hasher.update(modified.as_nanos().to_le_bytes());
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Finds the executable the same way launching a process would.
+fn resolve_executable(cwd: &Path, executable: &str) -> Option<PathBuf> {
+  let extensions = if cfg!(windows) {
+    std::env::var("PATHEXT")
+      .unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string())
+      .split(';')
+      .map(|ext| ext.to_string())
+      .collect::<Vec<_>>()
+  } else {
+    Vec::new()
+  };
+  let find_file = |path: PathBuf| {
+    if path.is_file() {
+      return Some(path);
+    }
+    extensions
+      .iter()
+      .map(|ext| PathBuf::from(format!("{}{}", path.display(), ext)))
+      .find(|path| path.is_file())
+  };
+
+  if executable.contains(['/', '\\']) {
+    return find_file(cwd.join(executable));
+  }
+  std::env::split_paths(&std::env::var_os("PATH")?).find_map(|dir| find_file(dir.join(executable)))
+}
+
+fn read_to_end_in_thread(
+  mut reader: impl Read + Send + 'static,
+) -> std::thread::JoinHandle<Vec<u8>> {
+  std::thread::spawn(move || {
+    let mut bytes = Vec::new();
+    let _ignore = reader.read_to_end(&mut bytes);
+    bytes
+  })
+}
+
+fn is_glob(path: &str) -> bool {
+  path.contains(['*', '?', '[', '{'])
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+  std::fs::read(path).map_err(|err| format!("Unable to read file '{}': {}.", path.display(), err))
+}
+
+fn hash_files(hasher: &mut Sha256, base_dir: &Path, files: &[PathBuf]) -> Result<(), String> {
+  for file in files {
+    // include the path so that adding, removing, or renaming a file changes the hash
+    hasher.update(relative_path_text(base_dir, file).as_bytes());
+    hasher.update(b"\0");
+    let contents = read_file(file)?;
+    hasher.update((contents.len() as u64).to_le_bytes());
+    hasher.update(contents);
+  }
+  Ok(())
+}
+
+/// Gets the path relative to the base directory using forward slashes so the
+/// hash is the same on every operating system and machine.
+fn relative_path_text(base_dir: &Path, path: &Path) -> String {
+  let relative = path.strip_prefix(base_dir).unwrap_or(path);
+  relative
+    .components()
+    .map(|c| c.as_os_str().to_string_lossy())
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+/// Gets the directory the glob matches within along with the files it matches.
+fn expand_glob(cwd: &Path, pattern: &str) -> Result<(PathBuf, Vec<PathBuf>), String> {
+  let pattern = pattern.strip_prefix("./").unwrap_or(pattern);
+  // only walk the directory the glob could match within, which may be
+  // outside the cwd (ex. `../shared/*.toml` or an absolute path)
+  let components = pattern.split('/').collect::<Vec<_>>();
+  let literal_count = components
+    .iter()
+    .take_while(|component| !is_glob(component))
+    .count();
+  let base_dir = match literal_count {
+    0 => cwd.to_path_buf(),
+    _ => cwd.join(components[..literal_count].join("/")),
+  };
+  let glob = components[literal_count..].join("/");
+  let matcher = GlobBuilder::new(&glob)
+    .literal_separator(true)
+    .build()
+    .map_err(|err| format!("Invalid glob '{}': {}", pattern, err))?
+    .compile_matcher();
+  let mut files = Vec::new();
+  if base_dir.is_dir() {
+    collect_files(&base_dir, &mut files)?;
+  }
+  files.retain(|file| matcher.is_match(relative_path_text(&base_dir, file)));
+  Ok((base_dir, files))
+}
+
+/// Recursively collects the files in a directory sorted by path. Symlinked
+/// directories aren't followed so that a symlink loop can't recurse forever.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+  let read_dir_err =
+    |err: std::io::Error| format!("Unable to read directory '{}': {}.", dir.display(), err);
+  let mut entries = std::fs::read_dir(dir)
+    .map_err(read_dir_err)?
+    .map(|entry| entry.map(|entry| entry.path()))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(read_dir_err)?;
+  entries.sort();
+  for path in entries {
+    let metadata = std::fs::symlink_metadata(&path)
+      .map_err(|err| format!("Unable to read '{}': {}.", path.display(), err))?;
+    if metadata.is_dir() {
+      collect_files(&path, files)?;
+    } else if !metadata.is_symlink() || !path.is_dir() {
+      files.push(path);
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  fn entry(path: &str, optional: bool) -> CacheKeyFileEntry {
+    CacheKeyFileEntry {
+      path: path.to_string(),
+      optional,
+    }
+  }
+
+  #[test]
+  fn hashes_command_stdout() {
+    let cwd = std::env::current_dir().unwrap();
+    let run = |code: &str, timeout: Duration| {
+      hash_cache_key_command(
+        &cwd,
+        "deno",
+        &["eval".to_string(), code.to_string()],
+        &BTreeMap::from([("VERSION".to_string(), "1.0.0".to_string())]),
+        timeout,
+      )
+    };
+    assert_eq!(
+      run(
+        "console.log(Deno.env.get('VERSION'))",
+        CACHE_KEY_COMMAND_TIMEOUT
+      )
+      .unwrap(),
+      format!("{:x}", Sha256::digest(b"1.0.0\n"))
+    );
+    assert!(
+      run(
+        "console.error('not found'); Deno.exit(1)",
+        CACHE_KEY_COMMAND_TIMEOUT
+      )
+      .unwrap_err()
+      .ends_with(": not found")
+    );
+    assert_eq!(
+      run("setTimeout(() => {}, 10_000)", Duration::from_millis(200)).unwrap_err(),
+      "Running 'deno' exceeded the timeout of 0.2s."
+    );
+  }
+
+  #[test]
+  fn hashes_executable() {
+    let cwd = std::env::current_dir().unwrap();
+    let hash = hash_cache_key_executable(&cwd, "deno").unwrap();
+    assert_eq!(hash, hash_cache_key_executable(&cwd, "deno").unwrap());
+    let relative_hash = hash_cache_key_executable(&cwd, "./tests/resources/binary.bin").unwrap();
+    assert_ne!(hash, relative_hash);
+    assert_eq!(
+      hash_cache_key_executable(&cwd, "missing-executable").unwrap_err(),
+      "Unable to find executable 'missing-executable' on the PATH."
+    );
+  }
+
+  #[test]
+  fn expands_globs_in_order() {
+    let cwd = std::env::current_dir().unwrap();
+    let (base_dir, files) = expand_glob(&cwd, "./tests/resources/*-line.txt").unwrap();
+    assert_eq!(base_dir, cwd.join("tests/resources"));
+    let files = files
+      .iter()
+      .map(|file| relative_path_text(&cwd, file))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      files,
+      vec![
+        "tests/resources/multi-line.txt",
+        "tests/resources/one-line.txt"
+      ]
+    );
+    // * doesn't match across directories
+    assert!(expand_glob(&cwd, "tests/*.txt").unwrap().1.is_empty());
+    assert!(expand_glob(&cwd, "tests/**/*.txt").unwrap().1.len() > 2);
+
+    // patterns outside the cwd are matched against their own base directory
+    let resources_dir = cwd.join("tests/resources");
+    let outside_files = expand_glob(&resources_dir, "../../src/*.rs").unwrap().1;
+    assert!(
+      outside_files
+        .iter()
+        .any(|file| file.ends_with("cache_key.rs"))
+    );
+    let absolute_pattern = format!(
+      "{}/*-line.txt",
+      resources_dir.to_string_lossy().replace('\\', "/")
+    );
+    assert_eq!(expand_glob(&cwd, &absolute_pattern).unwrap().1.len(), 2);
+    // the paths are hashed relative to the base directory, so they're the same on every machine
+    assert_eq!(
+      hash_cache_key_files(&cwd, &[entry(&absolute_pattern, false)]).unwrap(),
+      hash_cache_key_files(&cwd, &[entry("tests/resources/*-line.txt", false)]).unwrap(),
+    );
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn does_not_follow_symlinked_directories() {
+    let dir = std::env::temp_dir().join(format!("dprint-exec-cache-key-{}", std::process::id()));
+    let _ignore = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("sub/file.txt"), "text").unwrap();
+    std::os::unix::fs::symlink(&dir, dir.join("sub/loop")).unwrap();
+    let mut files = Vec::new();
+    collect_files(&dir, &mut files).unwrap();
+    assert_eq!(files, vec![dir.join("sub/file.txt")]);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn directory_same_as_glob_of_all_files() {
+    let cwd = std::env::current_dir().unwrap();
+    assert_eq!(
+      hash_cache_key_files(&cwd, &[entry("tests/resources", false)]).unwrap(),
+      hash_cache_key_files(&cwd, &[entry("tests/resources/**", false)]).unwrap(),
+    );
+  }
+
+  #[test]
+  fn optional_entries() {
+    let cwd = std::env::current_dir().unwrap();
+    assert!(hash_cache_key_files(&cwd, &[entry("missing.txt", true)]).is_ok());
+    assert!(hash_cache_key_files(&cwd, &[entry("missing/**/*.txt", true)]).is_ok());
+    assert!(
+      hash_cache_key_files(&cwd, &[entry("missing.txt", false)])
+        .unwrap_err()
+        .starts_with("Unable to read file")
+    );
+    assert_eq!(
+      hash_cache_key_files(&cwd, &[entry("missing/**/*.txt", false)]).unwrap_err(),
+      "No files matched the glob 'missing/**/*.txt'."
+    );
+  }
+}
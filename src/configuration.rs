@@ -5,19 +5,29 @@ use dprint_core::configuration::GlobalConfiguration;
 use dprint_core::configuration::RECOMMENDED_GLOBAL_CONFIGURATION;
 use dprint_core::configuration::ResolveConfigurationResult;
 use dprint_core::configuration::get_nullable_value;
-use dprint_core::configuration::get_nullable_vec;
 use dprint_core::configuration::get_unknown_property_diagnostics;
 use dprint_core::configuration::get_value;
 use globset::GlobMatcher;
-use handlebars::Handlebars;
+use regex::Regex;
 use serde::Serialize;
 use serde::Serializer;
 use sha2::Digest;
 use sha2::Sha256;
-use std::fs::read_to_string;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
 use std::path::Path;
 use std::path::PathBuf;
 
+use crate::cache_key::CACHE_KEY_COMMAND_TIMEOUT;
+use crate::cache_key::CacheKeyFileEntry;
+use crate::cache_key::hash_cache_key_command;
+use crate::cache_key::hash_cache_key_executable;
+use crate::cache_key::hash_cache_key_files;
+use crate::template::ArgTemplates;
+use crate::template::CommandTemplateVariables;
+use crate::template::expand_arg;
+use crate::template::render_template;
+
 #[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Configuration {
@@ -30,6 +40,19 @@ pub struct Configuration {
   /// Formatting commands to run
   pub commands: Vec<CommandConfiguration>,
   pub timeout: u32,
+  /// Whether to log the reason a command skipped formatting a file.
+  pub log_skipped: bool,
+  /// Whether to remember the output of commands for their input.
+  pub memoize: bool,
+  /// Directory to store remembered outputs in so they're shared across processes.
+  pub memoize_dir: Option<PathBuf>,
+  /// Command to run once before the first file is formatted.
+  pub before_all: Option<SetupCommand>,
+  /// Command to run once when the process shuts down.
+  pub after_all: Option<SetupCommand>,
+  /// Likely mistakes in the commands that don't make the configuration invalid.
+  #[serde(skip)]
+  pub warnings: Vec<ConfigurationDiagnostic>,
 }
 
 #[derive(Clone, Serialize)]
@@ -38,15 +61,160 @@ pub struct CommandConfiguration {
   pub executable: String,
   /// Executable arguments to add
   pub args: Vec<String>,
+  /// The arguments compiled as templates.
+  #[serde(skip)]
+  pub arg_templates: ArgTemplates,
   pub cwd: PathBuf,
+  /// User defined template variables.
+  pub vars: BTreeMap<String, String>,
+  /// Environment variables to set for the command.
+  pub env: BTreeMap<String, String>,
+  /// How the command formats files.
+  pub command_type: CommandType,
+  /// Whether the command formats or only checks files.
+  pub mode: CommandMode,
+  /// Service to send files to instead of spawning the command.
+  pub transport: Option<TransportConfiguration>,
+  /// What the command outputs.
+  pub output: CommandOutput,
+  /// What it means when the command outputs nothing.
+  pub empty_output: EmptyOutput,
+  /// Whether the command fails when it writes to stderr, even though it
+  /// exited successfully.
+  pub fail_on_stderr: bool,
   pub stdin: bool,
   #[serde(serialize_with = "serialize_glob")]
   pub associations: Option<GlobMatcher>,
   pub file_extensions: Vec<String>,
   pub file_names: Vec<String>,
+  /// Whether the commands after this one also format the files it matches.
+  pub chain: Option<CommandChain>,
   pub cache_key_files_hash: Option<String>,
+  pub cache_key_command_hash: Option<String>,
+  pub cache_key_executable_hash: Option<String>,
   /// Command to run once before this command formats its first file.
   pub setup_command: Option<SetupCommand>,
+  /// Only format the regions of the file found between these markers.
+  pub regions: Option<RegionsConfiguration>,
+  /// How the file is provided to the command.
+  pub input: CommandInput,
+  /// Parts of the file that are kept away from the command.
+  pub protect: ProtectConfiguration,
+  /// Files the command won't format.
+  pub skip: SkipConfiguration,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProtectConfiguration {
+  /// Whether blocks of lines between the ignore markers shouldn't be formatted.
+  pub ignore_blocks: bool,
+  /// Start and end markers of blocks of lines that shouldn't be formatted.
+  pub ignore_markers: (String, String),
+  /// Whether leading YAML or TOML front matter shouldn't be formatted.
+  pub front_matter: bool,
+}
+
+#[derive(Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SkipConfiguration {
+  /// Skip files containing any of these markers near the start of the file.
+  pub generated_markers: Vec<String>,
+  /// Skip files containing NUL bytes.
+  pub binary: bool,
+  /// Skip files larger than this number of bytes.
+  pub max_file_size: Option<u64>,
+}
+
+/// Configuration specified at the root that commands use unless they
+/// override it.
+#[derive(Default)]
+struct CommandDefaults {
+  cwd: Option<String>,
+  template_vars: CommandTemplateVariables,
+  protect: ProtectConfiguration,
+  skip: SkipConfiguration,
+}
+
+impl Default for ProtectConfiguration {
+  fn default() -> Self {
+    Self {
+      ignore_blocks: false,
+      ignore_markers: (
+        "dprint-ignore-start".to_string(),
+        "dprint-ignore-end".to_string(),
+      ),
+      front_matter: false,
+    }
+  }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum CommandType {
+  /// The command is run for each file and outputs the formatted text.
+  Exec,
+  /// The command is a language server that's kept running and formats
+  /// files via the Language Server Protocol.
+  Lsp {
+    /// Identifier of the files' language (default: the file extension).
+    language_id: Option<String>,
+  },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CommandChain {
+  /// The next matching command formats the output of this one.
+  Continue,
+  /// No further commands format the file.
+  Stop,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CommandMode {
+  /// The command's output replaces the text.
+  Format,
+  /// The command only validates the text (ex. `shellcheck`), so its output
+  /// is ignored and exiting with a non-zero code fails formatting the file.
+  Check,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CommandOutput {
+  /// The formatted text.
+  Text,
+  /// A unified diff to apply to the text (ex. `black --diff`).
+  UnifiedDiff,
+  /// Replacements to apply to the text in the XML format output by
+  /// `clang-format --output-replacements-xml`.
+  XmlReplacements,
+  /// A JSON object with the formatted text, whether it changed, and
+  /// diagnostics to report.
+  Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EmptyOutput {
+  /// The file is unchanged (ex. tools that only output changed files).
+  Unchanged,
+  /// The formatted text is empty.
+  Empty,
+  /// Formatting the file fails.
+  Error,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum CommandInput {
+  /// The file is provided to the command as-is.
+  File,
+  /// The file is a Jupyter notebook and each code cell whose kernel
+  /// language matches is provided to the command.
+  Notebook { language: String },
 }
 
 /// A command run a single time before formatting begins (ex. to install a
@@ -57,9 +225,91 @@ pub struct SetupCommand {
   pub executable: String,
   /// Executable arguments to add
   pub args: Vec<String>,
+  pub cwd: PathBuf,
+  /// Environment variables to set, which include the command's.
+  pub env: BTreeMap<String, String>,
+  /// Number of seconds to allow the setup to run for.
+  pub timeout: u32,
+  /// Whether to log the command's output.
+  pub log: bool,
+  pub retry: SetupRetry,
+  /// Directory to store stamp files in so the setup only runs once across
+  /// processes until its inputs change.
+  pub stamp_dir: Option<PathBuf>,
+}
+
+/// Whether a failed setup command is run again for the next file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SetupRetry {
+  /// Formatting each file reports the first failure.
+  Never,
+  /// The setup is run again for each file until it succeeds.
+  PerFile,
+  /// The setup is run again up to this many times.
+  Times(u32),
+}
+
+impl SetupRetry {
+  /// Gets if the setup may run again after failing this many times.
+  pub fn allows_retry(&self, failures: u32) -> bool {
+    match self {
+      SetupRetry::Never => false,
+      SetupRetry::PerFile => true,
+      SetupRetry::Times(times) => failures <= *times,
+    }
+  }
+}
+
+/// An already running service that formats files.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransportConfiguration {
+  /// Path of the Unix socket the service listens on.
+  pub unix_socket: PathBuf,
+}
+
+/// Start and end line markers of the embedded regions to format.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegionsConfiguration {
+  #[serde(serialize_with = "serialize_regex")]
+  pub start: Regex,
+  #[serde(serialize_with = "serialize_regex")]
+  pub end: Regex,
 }
 
 impl CommandConfiguration {
+  /// Whether the command formats the file, either by its associations or
+  /// otherwise its extensions and file names.
+  pub fn matches(&self, path: &Path) -> bool {
+    match &self.associations {
+      Some(associations) => associations.is_match(path),
+      None => self.matches_exts_or_filenames(path),
+    }
+  }
+
+  /// Whether no commands after this one format the files it matches, which
+  /// by default is only the case for commands without associations.
+  pub fn stops_chain(&self) -> bool {
+    self.chain.unwrap_or(self.default_chain()) == CommandChain::Stop
+  }
+
+  /// Whether `chain` is set to something other than its default, so
+  /// specifying the default behaves the same as omitting it.
+  pub fn overrides_chain(&self) -> bool {
+    self
+      .chain
+      .is_some_and(|chain| chain != self.default_chain())
+  }
+
+  fn default_chain(&self) -> CommandChain {
+    match self.associations {
+      Some(_) => CommandChain::Continue,
+      None => CommandChain::Stop,
+    }
+  }
+
   pub fn matches_exts_or_filenames(&self, path: &Path) -> bool {
     if let Some(filename) = path.file_name() {
       let filename = filename.to_string_lossy().to_lowercase();
@@ -82,6 +332,10 @@ fn serialize_glob<S: Serializer>(value: &Option<GlobMatcher>, s: S) -> Result<S:
   }
 }
 
+fn serialize_regex<S: Serializer>(value: &Regex, s: S) -> Result<S::Ok, S::Error> {
+  s.serialize_str(value.as_str())
+}
+
 impl Configuration {
   /// Resolves configuration from a collection of key value strings.
   ///
@@ -141,12 +395,46 @@ impl Configuration {
       ),
       commands: Vec::new(),
       timeout: get_value(&mut config, "timeout", 30, &mut diagnostics),
+      log_skipped: get_value(&mut config, "logSkipped", false, &mut diagnostics),
+      memoize: get_value(&mut config, "memoize", false, &mut diagnostics),
+      memoize_dir: None,
+      before_all: None,
+      after_all: None,
+      warnings: Vec::new(),
     };
 
     let root_cache_key = get_nullable_value::<String>(&mut config, "cacheKey", &mut diagnostics);
-    let mut cache_key_file_hashes = Vec::new();
+    let mut cache_key_hashes = Vec::new();
+    // index of each resolved command in the "commands" array
+    let mut command_indexes = Vec::new();
+
+    let command_defaults = {
+      let default = CommandDefaults::default();
+      let vars = parse_string_map(&mut config, "vars", &mut diagnostics);
+      CommandDefaults {
+        cwd: get_nullable_value(&mut config, "cwd", &mut diagnostics),
+        template_vars: CommandTemplateVariables::new(&resolved_config, vars),
+        protect: parse_protect(&mut config, &default.protect, &mut diagnostics),
+        skip: parse_skip(&mut config, &default.skip, &mut diagnostics),
+      }
+    };
+
+    let memoize_dir = get_nullable_value::<String>(&mut config, "memoizeDir", &mut diagnostics);
+    if memoize_dir.is_some() && !resolved_config.memoize {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: "memoizeDir".to_string(),
+        message: "Only supported when \"memoize\" is true.".to_string(),
+      });
+    }
+    resolved_config.memoize_dir =
+      memoize_dir.map(|dir| get_cwd(command_defaults.cwd.clone()).join(dir));
 
-    let root_cwd = get_nullable_value(&mut config, "cwd", &mut diagnostics);
+    (resolved_config.before_all, resolved_config.after_all) = parse_hooks(
+      &mut config,
+      &command_defaults,
+      resolved_config.timeout,
+      &mut diagnostics,
+    );
 
     if let Some(commands) = config.swap_remove("commands").and_then(|c| c.into_array()) {
       for (i, element) in commands.into_iter().enumerate() {
@@ -157,17 +445,24 @@ impl Configuration {
           });
           continue;
         };
-        let result = parse_command_obj(command_obj, root_cwd.as_ref());
+        let result = parse_command_obj(command_obj, &command_defaults);
         diagnostics.extend(result.1.into_iter().map(|mut diagnostic| {
           diagnostic.property_name = format!("commands[{}].{}", i, diagnostic.property_name);
           diagnostic
         }));
         if let Some(mut command_config) = result.0 {
           if let Some(cache_key_files_hash) = command_config.cache_key_files_hash.take() {
-            cache_key_file_hashes.push(cache_key_files_hash);
+            cache_key_hashes.push(cache_key_files_hash);
+          }
+          if let Some(cache_key_command_hash) = command_config.cache_key_command_hash.take() {
+            cache_key_hashes.push(cache_key_command_hash);
+          }
+          if let Some(cache_key_executable_hash) = command_config.cache_key_executable_hash.take() {
+            cache_key_hashes.push(cache_key_executable_hash);
           }
 
           resolved_config.commands.push(command_config);
+          command_indexes.push(i);
         }
       }
     } else {
@@ -178,8 +473,10 @@ impl Configuration {
     }
 
     diagnostics.extend(get_unknown_property_diagnostics(config));
+    resolved_config.warnings =
+      get_command_matching_warnings(&resolved_config.commands, &command_indexes);
 
-    if let Some(cache_key) = compute_cache_key(root_cache_key, &cache_key_file_hashes) {
+    if let Some(cache_key) = compute_cache_key(root_cache_key, &cache_key_hashes) {
       resolved_config.cache_key = cache_key;
     }
 
@@ -194,19 +491,15 @@ impl Configuration {
 
 fn parse_command_obj(
   mut command_obj: ConfigKeyMap,
-  root_cwd: Option<&String>,
+  defaults: &CommandDefaults,
 ) -> (Option<CommandConfiguration>, Vec<ConfigurationDiagnostic>) {
   let mut diagnostics = Vec::new();
-  let mut command = splitty::split_unquoted_whitespace(&get_value(
+  let mut command = split_command(&get_value(
     &mut command_obj,
     "command",
     String::default(),
     &mut diagnostics,
-  ))
-  .unwrap_quotes(true)
-  .filter(|p| !p.is_empty())
-  .map(String::from)
-  .collect::<Vec<_>>();
+  ));
   if command.is_empty() {
     diagnostics.push(ConfigurationDiagnostic {
       property_name: "command".to_string(),
@@ -215,70 +508,137 @@ fn parse_command_obj(
     return (None, diagnostics);
   }
 
-  {
-    let mut handlebars = Handlebars::new();
-    handlebars.set_strict_mode(true);
-    for arg in command.iter().skip(1) {
-      if let Err(e) = handlebars.register_template_string("tmp", arg) {
-        diagnostics.push(ConfigurationDiagnostic {
-          property_name: "command".to_string(),
-          message: format!("Invalid template: {}", e),
-        });
-      }
-      handlebars.unregister_template("tmp");
-    }
-  }
+  let mut template_vars = defaults.template_vars.clone();
+  template_vars
+    .vars
+    .extend(parse_string_map(&mut command_obj, "vars", &mut diagnostics));
 
   let cwd = get_cwd(
     get_nullable_value(&mut command_obj, "cwd", &mut diagnostics)
-      .or_else(|| root_cwd.map(ToOwned::to_owned)),
+      .or_else(|| defaults.cwd.clone())
+      .map(|cwd| render_config_template(&cwd, &template_vars, "cwd", &mut diagnostics)),
   );
+  template_vars.cwd = Some(cwd.to_string_lossy().to_string());
+
+  command[0] = render_config_template(&command[0], &template_vars, "command", &mut diagnostics);
+  let arg_templates = match ArgTemplates::compile(&command[1..], template_vars.clone()) {
+    Ok(arg_templates) => arg_templates,
+    Err(messages) => {
+      diagnostics.extend(messages.into_iter().map(|message| ConfigurationDiagnostic {
+        property_name: "command".to_string(),
+        message,
+      }));
+      ArgTemplates::default()
+    }
+  };
+
+  let env = parse_string_map(&mut command_obj, "env", &mut diagnostics)
+    .into_iter()
+    .map(|(name, value)| {
+      let property_name = format!("env.{}", name);
+      let value = render_config_template(&value, &template_vars, &property_name, &mut diagnostics);
+      (name, value)
+    })
+    .collect::<BTreeMap<_, _>>();
+
+  let cache_key_files = parse_cache_key_files(&mut command_obj, &mut diagnostics);
+
+  // compute the hash separately from the config read so we don't do the disk ops if the config is invalid.
+  let cache_key_files = cache_key_files.map(|entries| {
+    entries
+      .into_iter()
+      .enumerate()
+      .map(|(i, entry)| CacheKeyFileEntry {
+        path: render_config_template(
+          &entry.path,
+          &template_vars,
+          &format!("cacheKeyFiles[{}]", i),
+          &mut diagnostics,
+        ),
+        optional: entry.optional,
+      })
+      .collect::<Vec<_>>()
+  });
+  let cache_key_files_hash = match cache_key_files {
+    Some(cache_key_files) if diagnostics.is_empty() => {
+      match hash_cache_key_files(&cwd, &cache_key_files) {
+        Ok(hash) => Some(hash),
+        Err(message) => {
+          diagnostics.push(ConfigurationDiagnostic {
+            property_name: "cacheKeyFiles".to_string(),
+            message,
+          });
+          return (None, diagnostics);
+        }
+      }
+    }
+    _ => None,
+  };
+
+  let cache_key_command = parse_command_text(&mut command_obj, "cacheKeyCommand", &mut diagnostics);
+  let cache_key_command_hash = match cache_key_command {
+    Some((executable, args)) if diagnostics.is_empty() => {
+      match hash_cache_key_command(&cwd, &executable, &args, &env, CACHE_KEY_COMMAND_TIMEOUT) {
+        Ok(hash) => Some(hash),
+        Err(message) => {
+          diagnostics.push(ConfigurationDiagnostic {
+            property_name: "cacheKeyCommand".to_string(),
+            message,
+          });
+          return (None, diagnostics);
+        }
+      }
+    }
+    _ => None,
+  };
 
-  let cache_key_files = get_nullable_vec(
+  let cache_key_executable = get_value(
     &mut command_obj,
-    "cacheKeyFiles",
-    |value, i, diagnostics| match value {
-      ConfigKeyValue::String(value) => Some(cwd.join(value)),
-      _ => {
+    "cacheKeyExecutable",
+    false,
+    &mut diagnostics,
+  );
+  let cache_key_executable_hash = match cache_key_executable && diagnostics.is_empty() {
+    true => match hash_cache_key_executable(&cwd, &command[0]) {
+      Ok(hash) => Some(hash),
+      Err(message) => {
         diagnostics.push(ConfigurationDiagnostic {
-          property_name: format!("cacheKeyFiles[{}]", i),
-          message: "Expected string element.".to_string(),
+          property_name: "cacheKeyExecutable".to_string(),
+          message,
         });
-        None
+        return (None, diagnostics);
       }
     },
-    &mut diagnostics,
-  );
-
-  // compute the hash separately from the config read so we don't do the disk ops if the config is invalid.
-  let cache_key_files_hash = {
-    if let Some(cache_key_files) = cache_key_files {
-      let mut hasher = Sha256::new();
-      for file in cache_key_files {
-        let contents = match read_to_string(&file) {
-          Ok(contents) => contents,
-          Err(err) => {
-            diagnostics.push(ConfigurationDiagnostic {
-              property_name: "cacheKeyFiles".to_string(),
-              message: format!("Unable to read file '{}': {}.", file.display(), err),
-            });
-            return (None, diagnostics);
-          }
-        };
-        hasher.update(contents);
-      }
-      Some(format!("{:x}", hasher.finalize()))
-    } else {
-      None
-    }
+    false => None,
   };
 
-  let setup_command = parse_setup_command(&mut command_obj, &mut diagnostics);
+  let setup_command = parse_setup_command(
+    &mut command_obj,
+    &template_vars,
+    &cwd,
+    &env,
+    &mut diagnostics,
+  );
+  let command_type = parse_command_type(&mut command_obj, &mut diagnostics);
+  let mode = parse_mode(&mut command_obj, &mut diagnostics);
+  let chain = parse_chain(&mut command_obj, &mut diagnostics);
+  let transport = parse_transport(&mut command_obj, &template_vars, &cwd, &mut diagnostics);
+  let regions = parse_regions(&mut command_obj, &mut diagnostics);
+  let input = parse_input(&mut command_obj, &mut diagnostics);
+  let output = parse_output(&mut command_obj, &mut diagnostics);
+  let empty_output = parse_empty_output(&mut command_obj, &mut diagnostics);
+  let protect = parse_protect(&mut command_obj, &defaults.protect, &mut diagnostics);
+  let skip = parse_skip(&mut command_obj, &defaults.skip, &mut diagnostics);
 
-  let config = CommandConfiguration {
+  let mut config = CommandConfiguration {
     executable: command.remove(0),
     args: command,
+    arg_templates,
     setup_command,
+    regions,
+    input,
+    protect,
+    skip,
     associations: {
       let maybe_value = command_obj.swap_remove("associations").and_then(|value| match value {
         ConfigKeyValue::String(value) => Some(value),
@@ -328,6 +688,14 @@ fn parse_command_obj(
       })
     },
     cwd,
+    vars: template_vars.vars,
+    env,
+    command_type,
+    mode,
+    transport,
+    output,
+    empty_output,
+    fail_on_stderr: get_value(&mut command_obj, "failOnStderr", false, &mut diagnostics),
     stdin: get_value(&mut command_obj, "stdin", true, &mut diagnostics),
     file_extensions: take_string_or_string_vec(&mut command_obj, "exts", &mut diagnostics)
       .into_iter()
@@ -340,10 +708,97 @@ fn parse_command_obj(
       })
       .collect::<Vec<_>>(),
     file_names: take_string_or_string_vec(&mut command_obj, "fileNames", &mut diagnostics),
+    chain,
     cache_key_files_hash,
+    cache_key_command_hash,
+    cache_key_executable_hash,
   };
   diagnostics.extend(get_unknown_property_diagnostics(command_obj));
 
+  if config.regions.is_some() && !config.stdin {
+    diagnostics.push(ConfigurationDiagnostic {
+      property_name: "regions".to_string(),
+      message: "Regions can only be formatted when \"stdin\" is true.".to_string(),
+    });
+  }
+
+  if matches!(config.command_type, CommandType::Lsp { .. }) && !config.stdin {
+    diagnostics.push(ConfigurationDiagnostic {
+      property_name: "stdin".to_string(),
+      message: "Language servers are always provided the file text, so \"stdin\" must be true."
+        .to_string(),
+    });
+  }
+
+  if matches!(config.command_type, CommandType::Lsp { .. }) {
+    for (key, is_set) in [
+      ("mode", config.mode != CommandMode::Format),
+      ("transport", config.transport.is_some()),
+      ("output", config.output != CommandOutput::Text),
+      ("emptyOutput", config.empty_output != EmptyOutput::Empty),
+      ("failOnStderr", config.fail_on_stderr),
+    ] {
+      if is_set {
+        diagnostics.push(ConfigurationDiagnostic {
+          property_name: key.to_string(),
+          message: "Not supported when \"type\" is \"lsp\".".to_string(),
+        });
+      }
+    }
+  }
+
+  if config.mode == CommandMode::Check {
+    for (key, is_set) in [
+      (
+        "output",
+        !matches!(config.output, CommandOutput::Text | CommandOutput::Json),
+      ),
+      ("emptyOutput", config.empty_output != EmptyOutput::Empty),
+    ] {
+      if is_set {
+        diagnostics.push(ConfigurationDiagnostic {
+          property_name: key.to_string(),
+          message: "Not supported when \"mode\" is \"check\".".to_string(),
+        });
+      }
+    }
+  }
+
+  // patches are already unchanged when empty
+  if config.empty_output != EmptyOutput::Empty && config.output != CommandOutput::Text {
+    diagnostics.push(ConfigurationDiagnostic {
+      property_name: "emptyOutput".to_string(),
+      message: "Only supported when \"output\" is \"text\".".to_string(),
+    });
+  }
+
+  if matches!(config.input, CommandInput::Notebook { .. }) {
+    if config.regions.is_some() {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: "input".to_string(),
+        message: "Notebooks cannot be formatted by regions.".to_string(),
+      });
+    }
+    if !config.stdin {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: "input".to_string(),
+        message: "Notebooks can only be formatted when \"stdin\" is true.".to_string(),
+      });
+    }
+  }
+
+  // dprint only provides files to the plugin by their extensions and names
+  if let Some(associations) = &config.associations
+    && config.file_extensions.is_empty()
+    && config.file_names.is_empty()
+  {
+    // otherwise the files are only provided by the plugin's associations in the dprint config
+    if let Some((file_extensions, file_names)) = infer_file_matching(associations.glob().glob()) {
+      config.file_extensions = file_extensions;
+      config.file_names = file_names;
+    }
+  }
+
   if diagnostics.is_empty()
     && config.file_names.is_empty()
     && config.file_extensions.is_empty()
@@ -359,79 +814,832 @@ fn parse_command_obj(
   (Some(config), diagnostics)
 }
 
+/// Gets warnings for extensions, file names, and association globs of
+/// commands that are specified more than once or don't match files as expected.
+fn get_command_matching_warnings(
+  commands: &[CommandConfiguration],
+  command_indexes: &[usize],
+) -> Vec<ConfigurationDiagnostic> {
+  let mut warnings = Vec::new();
+  for (command, index) in commands.iter().zip(command_indexes) {
+    let mut seen_exts = HashSet::new();
+    for ext in &command.file_extensions {
+      if !seen_exts.insert(ext) {
+        warnings.push(ConfigurationDiagnostic {
+          property_name: format!("commands[{}].exts", index),
+          message: format!("The extension '{}' is specified more than once.", ext),
+        });
+      }
+    }
+
+    // only the extensions and file names of commands without associations are
+    // used to match files, which are compared to the lowercase file name
+    if command.associations.is_none() {
+      for ext in &command.file_extensions {
+        let lowercase_ext = ext.to_lowercase();
+        if *ext != lowercase_ext {
+          warnings.push(ConfigurationDiagnostic {
+            property_name: format!("commands[{}].exts", index),
+            message: format!(
+              "The extension '{}' never matches because file names are compared in lowercase. Use '{}' instead.",
+              ext, lowercase_ext
+            ),
+          });
+        }
+      }
+      for file_name in &command.file_names {
+        let lowercase_file_name = file_name.to_lowercase();
+        if *file_name != lowercase_file_name {
+          warnings.push(ConfigurationDiagnostic {
+            property_name: format!("commands[{}].fileNames", index),
+            message: format!(
+              "The file name '{}' never matches because file names are compared in lowercase. Use '{}' instead.",
+              file_name, lowercase_file_name
+            ),
+          });
+        }
+      }
+    }
+
+    // the inference in parse_command_obj fills these in when it's possible
+    if let Some(associations) = &command.associations
+      && command.file_extensions.is_empty()
+      && command.file_names.is_empty()
+    {
+      warnings.push(ConfigurationDiagnostic {
+        property_name: format!("commands[{}].associations", index),
+        message: format!(
+          concat!(
+            "Cannot infer the file extensions or names matched by '{}', so only the files ",
+            "matched by the plugin's associations in the dprint configuration are formatted. ",
+            "Specify \"exts\" or \"fileNames\" so dprint provides the files to this plugin."
+          ),
+          associations.glob().glob()
+        ),
+      });
+    }
+  }
+  warnings.extend(get_shadowed_command_warnings(commands, command_indexes));
+  warnings
+}
+
+/// Gets warnings for commands without associations that never format any
+/// files because every extension and file name they match is matched first by
+/// earlier commands that stop the chain.
+fn get_shadowed_command_warnings(
+  commands: &[CommandConfiguration],
+  command_indexes: &[usize],
+) -> Vec<ConfigurationDiagnostic> {
+  let mut warnings = Vec::new();
+  for (i, command) in commands.iter().enumerate() {
+    if command.associations.is_some() {
+      continue;
+    }
+    let earlier_commands = commands[..i]
+      .iter()
+      .enumerate()
+      .filter(|(_, earlier)| earlier.associations.is_none() && earlier.stops_chain())
+      .collect::<Vec<_>>();
+    // extensions and file names that aren't lowercase never match and are reported elsewhere
+    let claimed_exts = command
+      .file_extensions
+      .iter()
+      .filter(|ext| **ext == ext.to_lowercase())
+      .map(|ext| {
+        earlier_commands
+          .iter()
+          .find(|(_, earlier)| {
+            // ex. `.ts` also matches the `.d.ts` files
+            earlier
+              .file_extensions
+              .iter()
+              .any(|earlier_ext| ext.ends_with(earlier_ext.as_str()))
+          })
+          .map(|(index, _)| *index)
+      });
+    let claimed_file_names = command
+      .file_names
+      .iter()
+      .filter(|file_name| **file_name == file_name.to_lowercase())
+      .map(|file_name| {
+        earlier_commands
+          .iter()
+          .find(|(_, earlier)| earlier.matches_exts_or_filenames(Path::new(file_name)))
+          .map(|(index, _)| *index)
+      });
+    let Some(mut shadowing_indexes) = claimed_exts
+      .chain(claimed_file_names)
+      .collect::<Option<Vec<_>>>()
+    else {
+      continue;
+    };
+    if shadowing_indexes.is_empty() {
+      continue;
+    }
+    shadowing_indexes.sort();
+    shadowing_indexes.dedup();
+    warnings.push(ConfigurationDiagnostic {
+      property_name: format!(
+        "commands[{}].{}",
+        command_indexes[i],
+        if command.file_extensions.is_empty() {
+          "fileNames"
+        } else {
+          "exts"
+        }
+      ),
+      message: format!(
+        "Never formats any files because they are all matched first by {}.",
+        shadowing_indexes
+          .iter()
+          .map(|index| format!("commands[{}]", command_indexes[*index]))
+          .collect::<Vec<_>>()
+          .join(", ")
+      ),
+    });
+  }
+  warnings
+}
+
+/// Infers the file extensions and names a glob matches from the literal end
+/// of its last path component (ex. `**/*.tf` matches `.tf` files and
+/// `**/{Dockerfile,*.dockerfile}` matches `Dockerfile` and `.dockerfile` files).
+fn infer_file_matching(glob: &str) -> Option<(Vec<String>, Vec<String>)> {
+  fn is_literal(text: &str) -> bool {
+    !text.is_empty() && !text.contains(['*', '?', '[', ']', '{', '}', '\\', '/'])
+  }
+
+  let name = match glob.rfind('/') {
+    // the separator is within alternatives (ex. `{src/*.ts,*.js}`)
+    Some(index) if glob[index..].contains('}') && !glob[index..].contains('{') => return None,
+    Some(index) => &glob[index + 1..],
+    None => glob,
+  };
+  // expand a single group of alternatives
+  let candidates = match name.split_once('{') {
+    Some((prefix, rest)) => {
+      let (alternatives, suffix) = rest.split_once('}')?;
+      alternatives
+        .split(',')
+        .map(|alternative| format!("{}{}{}", prefix, alternative, suffix))
+        .collect::<Vec<_>>()
+    }
+    None => vec![name.to_string()],
+  };
+
+  let mut file_extensions = Vec::new();
+  let mut file_names = Vec::new();
+  for candidate in candidates {
+    match candidate.strip_prefix("*.") {
+      Some(ext) if is_literal(ext) => file_extensions.push(format!(".{}", ext)),
+      None if is_literal(&candidate) => file_names.push(candidate),
+      _ => return None,
+    }
+  }
+  Some((file_extensions, file_names))
+}
+
 fn parse_setup_command(
   command_obj: &mut ConfigKeyMap,
+  template_vars: &CommandTemplateVariables,
+  command_cwd: &Path,
+  command_env: &BTreeMap<String, String>,
   diagnostics: &mut Vec<ConfigurationDiagnostic>,
 ) -> Option<SetupCommand> {
-  let raw = get_nullable_value::<String>(command_obj, "setupCommand", diagnostics)?;
-  let mut parts = splitty::split_unquoted_whitespace(&raw)
-    .unwrap_quotes(true)
-    .filter(|p| !p.is_empty())
-    .map(String::from)
-    .collect::<Vec<_>>();
-  if parts.is_empty() {
-    diagnostics.push(ConfigurationDiagnostic {
-      property_name: "setupCommand".to_string(),
-      message: "Expected to find a command name.".to_string(),
-    });
+  let command_text = parse_command_text(command_obj, "setupCommand", diagnostics);
+  let setup_cwd = get_nullable_value::<String>(command_obj, "setupCwd", diagnostics);
+  let setup_env = parse_string_map(command_obj, "setupEnv", diagnostics);
+  let timeout = get_nullable_value::<u32>(command_obj, "setupTimeout", diagnostics);
+  let log = get_nullable_value::<bool>(command_obj, "setupLog", diagnostics);
+  let retry = parse_setup_retry(command_obj, diagnostics);
+  let stamp_dir = get_nullable_value::<String>(command_obj, "setupStampDir", diagnostics);
+  let Some((executable, args)) = command_text else {
+    for (key, is_set) in [
+      ("setupCwd", setup_cwd.is_some()),
+      ("setupEnv", !setup_env.is_empty()),
+      ("setupTimeout", timeout.is_some()),
+      ("setupLog", log.is_some()),
+      ("setupRetry", retry.is_some()),
+      ("setupStampDir", stamp_dir.is_some()),
+    ] {
+      if is_set {
+        diagnostics.push(ConfigurationDiagnostic {
+          property_name: key.to_string(),
+          message: "Only supported when \"setupCommand\" is set.".to_string(),
+        });
+      }
+    }
     return None;
+  };
+
+  let cwd = match setup_cwd {
+    Some(setup_cwd) => command_cwd.join(render_config_template(
+      &setup_cwd,
+      template_vars,
+      "setupCwd",
+      diagnostics,
+    )),
+    None => command_cwd.to_path_buf(),
+  };
+  let mut env = command_env.clone();
+  for (name, value) in setup_env {
+    let property_name = format!("setupEnv.{}", name);
+    let value = render_config_template(&value, template_vars, &property_name, diagnostics);
+    env.insert(name, value);
   }
   Some(SetupCommand {
-    executable: parts.remove(0),
-    args: parts,
+    executable: render_config_template(&executable, template_vars, "setupCommand", diagnostics),
+    args: render_config_args(&args, template_vars, "setupCommand", diagnostics),
+    cwd,
+    env,
+    timeout: timeout.unwrap_or(template_vars.timeout),
+    log: log.unwrap_or(false),
+    retry: retry.unwrap_or(SetupRetry::PerFile),
+    stamp_dir: stamp_dir.map(|dir| {
+      command_cwd.join(render_config_template(
+        &dir,
+        template_vars,
+        "setupStampDir",
+        diagnostics,
+      ))
+    }),
   })
 }
 
-fn take_string_or_string_vec(
-  command_obj: &mut ConfigKeyMap,
-  key: &str,
+/// Parses the `beforeAll` and `afterAll` commands, which run in the root
+/// cwd and share the `hookTimeout` and `hookLog` options.
+fn parse_hooks(
+  config: &mut ConfigKeyMap,
+  defaults: &CommandDefaults,
+  default_timeout: u32,
   diagnostics: &mut Vec<ConfigurationDiagnostic>,
-) -> Vec<String> {
-  command_obj
-    .swap_remove(key)
-    .map(|values| match values {
-      ConfigKeyValue::String(value) => vec![value],
-      ConfigKeyValue::Array(elements) => {
-        let mut values = Vec::with_capacity(elements.len());
-        for (i, element) in elements.into_iter().enumerate() {
-          match element {
-            ConfigKeyValue::String(value) => {
-              values.push(value);
-            }
-            _ => diagnostics.push(ConfigurationDiagnostic {
-              property_name: format!("{}[{}]", key, i),
-              message: "Expected string element.".to_string(),
-            }),
-          }
-        }
-        values
-      }
-      _ => {
+) -> (Option<SetupCommand>, Option<SetupCommand>) {
+  let cwd = get_cwd(defaults.cwd.clone());
+  let mut template_vars = defaults.template_vars.clone();
+  template_vars.cwd = Some(cwd.to_string_lossy().to_string());
+  let timeout = get_nullable_value::<u32>(config, "hookTimeout", diagnostics);
+  let log = get_nullable_value::<bool>(config, "hookLog", diagnostics);
+  let mut parse_hook = |key: &str| {
+    let (executable, args) = parse_command_text(config, key, diagnostics)?;
+    Some(SetupCommand {
+      executable: render_config_template(&executable, &template_vars, key, diagnostics),
+      args: render_config_args(&args, &template_vars, key, diagnostics),
+      cwd: cwd.clone(),
+      env: BTreeMap::new(),
+      // a hung hook would otherwise block formatting or plugin shutdown forever
+      timeout: timeout.unwrap_or(default_timeout),
+      log: log.unwrap_or(false),
+      retry: SetupRetry::PerFile,
+      stamp_dir: None,
+    })
+  };
+  let before_all = parse_hook("beforeAll");
+  let after_all = parse_hook("afterAll");
+  if before_all.is_none() && after_all.is_none() {
+    for (key, is_set) in [
+      ("hookTimeout", timeout.is_some()),
+      ("hookLog", log.is_some()),
+    ] {
+      if is_set {
         diagnostics.push(ConfigurationDiagnostic {
           property_name: key.to_string(),
-          message: "Expected string or array value.".to_string(),
+          message: "Only supported when \"beforeAll\" or \"afterAll\" is set.".to_string(),
         });
-        vec![]
       }
-    })
-    .unwrap_or_default()
+    }
+  }
+  (before_all, after_all)
 }
 
-fn get_cwd(dir: Option<String>) -> PathBuf {
-  match dir {
-    Some(dir) => PathBuf::from(dir),
+fn parse_setup_retry(
+  command_obj: &mut ConfigKeyMap,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> Option<SetupRetry> {
+  let value = get_nullable_value::<String>(command_obj, "setupRetry", diagnostics)?;
+  let retry = match value.as_str() {
+    "never" => Some(SetupRetry::Never),
+    "perFile" => Some(SetupRetry::PerFile),
+    _ => value
+      .strip_prefix("times:")
+      .and_then(|times| times.parse().ok())
+      .map(SetupRetry::Times),
+  };
+  if retry.is_none() {
+    diagnostics.push(ConfigurationDiagnostic {
+      property_name: "setupRetry".to_string(),
+      message: "Expected \"never\", \"perFile\", or \"times:N\" (ex. \"times:3\").".to_string(),
+    });
+  }
+  retry
+}
+
+/// Splits command text into its executable and arguments. Whitespace within
+/// a template expression (ex. `{{#if use_tabs}}`) doesn't split an argument.
+fn split_command(text: &str) -> Vec<String> {
+  let mut parts: Vec<String> = Vec::new();
+  let mut open_expressions = 0;
+  for part in splitty::split_unquoted_whitespace(text)
+    .unwrap_quotes(true)
+    .filter(|p| !p.is_empty())
+  {
+    match parts.last_mut() {
+      Some(last) if open_expressions > 0 => {
+        last.push(' ');
+        last.push_str(part);
+      }
+      _ => parts.push(part.to_string()),
+    }
+    open_expressions += part.matches("{{").count() as isize;
+    open_expressions -= part.matches("}}").count() as isize;
+  }
+  parts
+}
+
+/// Renders a template that doesn't depend on the file being formatted,
+/// reporting a diagnostic and keeping the text as-is on failure.
+fn render_config_template(
+  template: &str,
+  template_vars: &CommandTemplateVariables,
+  property_name: &str,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> String {
+  let template_vars = template_vars.clone().with_env_for([template]);
+  render_template(template, &template_vars).unwrap_or_else(|message| {
+    diagnostics.push(ConfigurationDiagnostic {
+      property_name: property_name.to_string(),
+      message,
+    });
+    template.to_string()
+  })
+}
+
+/// Renders the arguments of a command that doesn't depend on the file being
+/// formatted, expanding each to the argv entries it renders to.
+fn render_config_args(
+  args: &[String],
+  template_vars: &CommandTemplateVariables,
+  property_name: &str,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> Vec<String> {
+  let mut result = Vec::with_capacity(args.len());
+  for arg in args {
+    let template_vars = template_vars.clone().with_env_for([arg.as_str()]);
+    if let Err(message) = template_vars.check_separator([arg.as_str()]) {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: property_name.to_string(),
+        message,
+      });
+      continue;
+    }
+    let rendered = render_config_template(arg, &template_vars, property_name, diagnostics);
+    result.extend(expand_arg(&rendered));
+  }
+  result
+}
+
+/// Takes an object property whose values are strings, numbers, or booleans.
+fn parse_string_map(
+  obj: &mut ConfigKeyMap,
+  key: &str,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> BTreeMap<String, String> {
+  let Some(value) = obj.swap_remove(key) else {
+    return BTreeMap::new();
+  };
+  let ConfigKeyValue::Object(values) = value else {
+    diagnostics.push(ConfigurationDiagnostic {
+      property_name: key.to_string(),
+      message: "Expected an object.".to_string(),
+    });
+    return BTreeMap::new();
+  };
+  values
+    .into_iter()
+    .filter_map(|(name, value)| {
+      let value = match value {
+        ConfigKeyValue::String(value) => value,
+        ConfigKeyValue::Number(value) => value.to_string(),
+        ConfigKeyValue::Bool(value) => value.to_string(),
+        _ => {
+          diagnostics.push(ConfigurationDiagnostic {
+            property_name: format!("{}.{}", key, name),
+            message: "Expected a string, number, or boolean value.".to_string(),
+          });
+          return None;
+        }
+      };
+      Some((name, value))
+    })
+    .collect()
+}
+
+/// Splits a command string property into its executable and arguments.
+fn parse_command_text(
+  command_obj: &mut ConfigKeyMap,
+  key: &str,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> Option<(String, Vec<String>)> {
+  let raw = get_nullable_value::<String>(command_obj, key, diagnostics)?;
+  let mut parts = split_command(&raw);
+  if parts.is_empty() {
+    diagnostics.push(ConfigurationDiagnostic {
+      property_name: key.to_string(),
+      message: "Expected to find a command name.".to_string(),
+    });
+    return None;
+  }
+  Some((parts.remove(0), parts))
+}
+
+fn parse_chain(
+  command_obj: &mut ConfigKeyMap,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> Option<CommandChain> {
+  let chain = get_nullable_value::<String>(command_obj, "chain", diagnostics)?;
+  match chain.as_str() {
+    "continue" => Some(CommandChain::Continue),
+    "stop" => Some(CommandChain::Stop),
+    _ => {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: "chain".to_string(),
+        message: format!(
+          "Unknown chain '{}'. Expected \"continue\" or \"stop\".",
+          chain
+        ),
+      });
+      None
+    }
+  }
+}
+
+fn parse_mode(
+  command_obj: &mut ConfigKeyMap,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> CommandMode {
+  let mode = get_value(command_obj, "mode", "format".to_string(), diagnostics);
+  match mode.as_str() {
+    "format" => CommandMode::Format,
+    "check" => CommandMode::Check,
+    _ => {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: "mode".to_string(),
+        message: format!("Unknown mode '{}'. Expected \"format\" or \"check\".", mode),
+      });
+      CommandMode::Format
+    }
+  }
+}
+
+fn parse_output(
+  command_obj: &mut ConfigKeyMap,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> CommandOutput {
+  let output = get_value(command_obj, "output", "text".to_string(), diagnostics);
+  match output.as_str() {
+    "text" => CommandOutput::Text,
+    "unifiedDiff" => CommandOutput::UnifiedDiff,
+    "xmlReplacements" => CommandOutput::XmlReplacements,
+    "json" => CommandOutput::Json,
+    _ => {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: "output".to_string(),
+        message: format!(
+          "Unknown output '{}'. Expected \"text\", \"unifiedDiff\", \"xmlReplacements\", or \"json\".",
+          output
+        ),
+      });
+      CommandOutput::Text
+    }
+  }
+}
+
+fn parse_empty_output(
+  command_obj: &mut ConfigKeyMap,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> EmptyOutput {
+  let empty_output = get_value(command_obj, "emptyOutput", "empty".to_string(), diagnostics);
+  match empty_output.as_str() {
+    "unchanged" => EmptyOutput::Unchanged,
+    "empty" => EmptyOutput::Empty,
+    "error" => EmptyOutput::Error,
+    _ => {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: "emptyOutput".to_string(),
+        message: format!(
+          "Unknown empty output '{}'. Expected \"unchanged\", \"empty\", or \"error\".",
+          empty_output
+        ),
+      });
+      EmptyOutput::Empty
+    }
+  }
+}
+
+fn parse_transport(
+  command_obj: &mut ConfigKeyMap,
+  template_vars: &CommandTemplateVariables,
+  command_cwd: &Path,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> Option<TransportConfiguration> {
+  let value = command_obj.swap_remove("transport")?;
+  let Some(mut transport_obj) = value.into_object() else {
+    diagnostics.push(ConfigurationDiagnostic {
+      property_name: "transport".to_string(),
+      message: "Expected an object with a \"unixSocket\" property.".to_string(),
+    });
+    return None;
+  };
+  let mut transport_diagnostics = Vec::new();
+  let unix_socket =
+    get_nullable_value::<String>(&mut transport_obj, "unixSocket", &mut transport_diagnostics);
+  if unix_socket.is_none() && transport_diagnostics.is_empty() {
+    transport_diagnostics.push(ConfigurationDiagnostic {
+      property_name: "unixSocket".to_string(),
+      message: "Expected the path of a Unix socket.".to_string(),
+    });
+  }
+  let unix_socket = unix_socket.map(|path| {
+    command_cwd.join(render_config_template(
+      &path,
+      template_vars,
+      "unixSocket",
+      &mut transport_diagnostics,
+    ))
+  });
+  transport_diagnostics.extend(get_unknown_property_diagnostics(transport_obj));
+  diagnostics.extend(transport_diagnostics.into_iter().map(|mut diagnostic| {
+    diagnostic.property_name = format!("transport.{}", diagnostic.property_name);
+    diagnostic
+  }));
+  Some(TransportConfiguration {
+    unix_socket: unix_socket?,
+  })
+}
+
+fn parse_cache_key_files(
+  command_obj: &mut ConfigKeyMap,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> Option<Vec<CacheKeyFileEntry>> {
+  let value = command_obj.swap_remove("cacheKeyFiles")?;
+  let Some(values) = value.into_array() else {
+    diagnostics.push(ConfigurationDiagnostic {
+      property_name: "cacheKeyFiles".to_string(),
+      message: "Expected an array.".to_string(),
+    });
+    return None;
+  };
+  Some(
+    values
+      .into_iter()
+      .enumerate()
+      .filter_map(|(i, value)| match value {
+        ConfigKeyValue::String(path) => Some(CacheKeyFileEntry {
+          path,
+          optional: false,
+        }),
+        ConfigKeyValue::Object(mut obj) => {
+          let mut entry_diagnostics = Vec::new();
+          let path = get_nullable_value::<String>(&mut obj, "path", &mut entry_diagnostics);
+          let optional = get_value(&mut obj, "optional", false, &mut entry_diagnostics);
+          if path.is_none() && entry_diagnostics.is_empty() {
+            entry_diagnostics.push(ConfigurationDiagnostic {
+              property_name: "path".to_string(),
+              message: "Expected to find a path.".to_string(),
+            });
+          }
+          entry_diagnostics.extend(get_unknown_property_diagnostics(obj));
+          diagnostics.extend(entry_diagnostics.into_iter().map(|mut diagnostic| {
+            diagnostic.property_name = format!("cacheKeyFiles[{}].{}", i, diagnostic.property_name);
+            diagnostic
+          }));
+          Some(CacheKeyFileEntry {
+            path: path?,
+            optional,
+          })
+        }
+        _ => {
+          diagnostics.push(ConfigurationDiagnostic {
+            property_name: format!("cacheKeyFiles[{}]", i),
+            message: "Expected string or object element.".to_string(),
+          });
+          None
+        }
+      })
+      .collect(),
+  )
+}
+
+fn parse_regions(
+  command_obj: &mut ConfigKeyMap,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> Option<RegionsConfiguration> {
+  let value = command_obj.swap_remove("regions")?;
+  let Some(mut regions_obj) = value.into_object() else {
+    diagnostics.push(ConfigurationDiagnostic {
+      property_name: "regions".to_string(),
+      message: "Expected an object with \"start\" and \"end\" properties.".to_string(),
+    });
+    return None;
+  };
+  let mut regions_diagnostics = Vec::new();
+  let mut get_regex = |key: &str| {
+    let value = get_value(
+      &mut regions_obj,
+      key,
+      String::default(),
+      &mut regions_diagnostics,
+    );
+    if value.is_empty() {
+      regions_diagnostics.push(ConfigurationDiagnostic {
+        property_name: key.to_string(),
+        message: "Expected a non-empty regular expression.".to_string(),
+      });
+      return None;
+    }
+    match Regex::new(&value) {
+      Ok(regex) => Some(regex),
+      Err(err) => {
+        regions_diagnostics.push(ConfigurationDiagnostic {
+          property_name: key.to_string(),
+          message: format!("Invalid regular expression: {}", err),
+        });
+        None
+      }
+    }
+  };
+  let start = get_regex("start");
+  let end = get_regex("end");
+  regions_diagnostics.extend(get_unknown_property_diagnostics(regions_obj));
+  diagnostics.extend(regions_diagnostics.into_iter().map(|mut diagnostic| {
+    diagnostic.property_name = format!("regions.{}", diagnostic.property_name);
+    diagnostic
+  }));
+  Some(RegionsConfiguration {
+    start: start?,
+    end: end?,
+  })
+}
+
+fn parse_command_type(
+  command_obj: &mut ConfigKeyMap,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> CommandType {
+  let command_type = get_value(command_obj, "type", "exec".to_string(), diagnostics);
+  let language_id = get_nullable_value::<String>(command_obj, "lspLanguageId", diagnostics);
+  match command_type.as_str() {
+    "exec" => {
+      if language_id.is_some() {
+        diagnostics.push(ConfigurationDiagnostic {
+          property_name: "lspLanguageId".to_string(),
+          message: "Only supported when \"type\" is \"lsp\".".to_string(),
+        });
+      }
+      CommandType::Exec
+    }
+    "lsp" => CommandType::Lsp { language_id },
+    _ => {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: "type".to_string(),
+        message: format!(
+          "Unknown type '{}'. Expected \"exec\" or \"lsp\".",
+          command_type
+        ),
+      });
+      CommandType::Exec
+    }
+  }
+}
+
+fn parse_input(
+  command_obj: &mut ConfigKeyMap,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> CommandInput {
+  let input = get_value(command_obj, "input", "file".to_string(), diagnostics);
+  let language = get_nullable_value::<String>(command_obj, "notebookLanguage", diagnostics);
+  match input.as_str() {
+    "file" => {
+      if language.is_some() {
+        diagnostics.push(ConfigurationDiagnostic {
+          property_name: "notebookLanguage".to_string(),
+          message: "Only supported when \"input\" is \"notebook\".".to_string(),
+        });
+      }
+      CommandInput::File
+    }
+    "notebook" => CommandInput::Notebook {
+      language: language.unwrap_or_else(|| "python".to_string()),
+    },
+    _ => {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: "input".to_string(),
+        message: format!(
+          "Unknown input '{}'. Expected \"file\" or \"notebook\".",
+          input
+        ),
+      });
+      CommandInput::File
+    }
+  }
+}
+
+fn parse_protect(
+  config: &mut ConfigKeyMap,
+  default: &ProtectConfiguration,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> ProtectConfiguration {
+  let mut get_marker = |key: &str, default_marker: &String| {
+    let marker = get_value(config, key, default_marker.clone(), diagnostics);
+    if marker.is_empty() {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: key.to_string(),
+        message: "Expected a non-empty marker.".to_string(),
+      });
+      return default_marker.clone();
+    }
+    marker
+  };
+  let ignore_markers = (
+    get_marker("ignoreStartMarker", &default.ignore_markers.0),
+    get_marker("ignoreEndMarker", &default.ignore_markers.1),
+  );
+  ProtectConfiguration {
+    ignore_blocks: get_value(config, "ignoreBlocks", default.ignore_blocks, diagnostics),
+    ignore_markers,
+    front_matter: get_value(config, "frontMatter", default.front_matter, diagnostics),
+  }
+}
+
+fn parse_skip(
+  config: &mut ConfigKeyMap,
+  default: &SkipConfiguration,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> SkipConfiguration {
+  let generated_markers = if config.contains_key("skipGeneratedMarkers") {
+    let markers = take_string_or_string_vec(config, "skipGeneratedMarkers", diagnostics);
+    if markers.iter().any(|marker| marker.is_empty()) {
+      diagnostics.push(ConfigurationDiagnostic {
+        property_name: "skipGeneratedMarkers".to_string(),
+        message: "Expected non-empty markers.".to_string(),
+      });
+    }
+    markers
+  } else {
+    default.generated_markers.clone()
+  };
+  SkipConfiguration {
+    generated_markers,
+    binary: get_value(config, "skipBinary", default.binary, diagnostics),
+    max_file_size: get_nullable_value(config, "maxFileSize", diagnostics).or(default.max_file_size),
+  }
+}
+
+fn take_string_or_string_vec(
+  command_obj: &mut ConfigKeyMap,
+  key: &str,
+  diagnostics: &mut Vec<ConfigurationDiagnostic>,
+) -> Vec<String> {
+  command_obj
+    .swap_remove(key)
+    .map(|values| match values {
+      ConfigKeyValue::String(value) => vec![value],
+      ConfigKeyValue::Array(elements) => {
+        let mut values = Vec::with_capacity(elements.len());
+        for (i, element) in elements.into_iter().enumerate() {
+          match element {
+            ConfigKeyValue::String(value) => {
+              values.push(value);
+            }
+            _ => diagnostics.push(ConfigurationDiagnostic {
+              property_name: format!("{}[{}]", key, i),
+              message: "Expected string element.".to_string(),
+            }),
+          }
+        }
+        values
+      }
+      _ => {
+        diagnostics.push(ConfigurationDiagnostic {
+          property_name: key.to_string(),
+          message: "Expected string or array value.".to_string(),
+        });
+        vec![]
+      }
+    })
+    .unwrap_or_default()
+}
+
+fn get_cwd(dir: Option<String>) -> PathBuf {
+  match dir {
+    Some(dir) => PathBuf::from(dir),
     None => std::env::current_dir().expect("should get cwd"),
   }
 }
 
 fn compute_cache_key(
   root_cache_key: Option<String>,
-  cache_key_file_hashes: &[String],
+  cache_key_hashes: &[String],
 ) -> Option<String> {
-  match (
-    root_cache_key,
-    compute_cache_key_files_hash(cache_key_file_hashes),
-  ) {
+  match (root_cache_key, combine_cache_key_hashes(cache_key_hashes)) {
     (Some(root), Some(files)) => Some(format!("{}{}", root, files)),
     (Some(root), None) => Some(root),
     (None, Some(files)) => Some(files),
@@ -439,14 +1647,14 @@ fn compute_cache_key(
   }
 }
 
-fn compute_cache_key_files_hash(cache_key_file_hashes: &[String]) -> Option<String> {
-  if cache_key_file_hashes.is_empty() {
+fn combine_cache_key_hashes(cache_key_hashes: &[String]) -> Option<String> {
+  if cache_key_hashes.is_empty() {
     return None;
   }
 
   let mut hasher = Sha256::new();
-  for file_hash in cache_key_file_hashes {
-    hasher.update(file_hash);
+  for hash in cache_key_hashes {
+    hasher.update(hash);
   }
   Some(format!("{:x}", hasher.finalize()))
 }
@@ -474,156 +1682,1271 @@ mod tests {
   }
 
   #[test]
-  fn general_test() {
+  fn general_test() {
+    let unresolved_config = parse_config(json!({
+      "cacheKey": "2",
+      "timeout": 5
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    let config = result.config;
+    assert_eq!(config.line_width, 120);
+    assert_eq!(config.indent_width, 2);
+    assert!(!config.use_tabs);
+    assert_eq!(config.cache_key, "2");
+    assert_eq!(config.timeout, 5);
+    assert_eq!(result.diagnostics, vec![ConfigurationDiagnostic {
+      property_name: "commands".to_string(),
+      message: "Expected to find a \"commands\" array property (see https://github.com/dprint/dprint-plugin-exec for instructions)".to_string(),
+    }]);
+  }
+
+  #[test]
+  fn empty_command_name() {
+    let config = parse_config(json!({
+      "commands": [{
+        "command": "",
+      }],
+    }));
+    run_diagnostics_test(
+      config,
+      vec![ConfigurationDiagnostic {
+        property_name: "commands[0].command".to_string(),
+        message: "Expected to find a command name.".to_string(),
+      }],
+    )
+  }
+
+  #[test]
+  fn command_templates() {
+    let config = parse_config(json!({
+      "commands": [{
+        "exts": ["txt"],
+        "command": "formatter {{file_stem}} --indent={{indent_string}} {{file_nme}}",
+      }],
+    }));
+    run_diagnostics_test(
+      config,
+      vec![ConfigurationDiagnostic {
+        property_name: "commands[0].command".to_string(),
+        message: "Unknown variable 'file_nme' in template '{{file_nme}}'.".to_string(),
+      }],
+    )
+  }
+
+  #[test]
+  fn vars_and_env() {
+    let unresolved_config = parse_config(json!({
+      "vars": { "tools": "tools", "style": "google" },
+      "commands": [{
+        "exts": ["py"],
+        "vars": { "style": "pep8", "width": 79 },
+        "cwd": "{{vars.tools}}",
+        "command": "{{cwd}}/bin/yapf --style={{vars.style}} --width={{vars.width}}",
+        "cacheKeyFiles": ["{{env.CARGO_MANIFEST_DIR}}/Cargo.toml"],
+        "setupCommand": "{{cwd}}/install.sh {{vars.style}}",
+        "env": { "YAPF_HOME": "{{cwd}}/yapf", "DEBUG": true },
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    let command = &result.config.commands[0];
+    assert_eq!(command.cwd, PathBuf::from("tools"));
+    assert_eq!(command.executable, "tools/bin/yapf");
+    assert_eq!(
+      command.args,
+      vec!["--style={{vars.style}}", "--width={{vars.width}}"]
+    );
+    assert_eq!(
+      command.vars,
+      BTreeMap::from([
+        ("style".to_string(), "pep8".to_string()),
+        ("tools".to_string(), "tools".to_string()),
+        ("width".to_string(), "79".to_string()),
+      ])
+    );
+    let setup_command = command.setup_command.as_ref().unwrap();
+    assert_eq!(setup_command.executable, "tools/install.sh");
+    assert_eq!(setup_command.args, vec!["pep8"]);
+    assert_eq!(
+      command.env,
+      BTreeMap::from([
+        ("DEBUG".to_string(), "true".to_string()),
+        ("YAPF_HOME".to_string(), "tools/yapf".to_string()),
+      ])
+    );
+  }
+
+  #[test]
+  fn vars_are_not_html_escaped() {
+    let unresolved_config = parse_config(json!({
+      "vars": { "flag": "--x=1&y<2", "quoted": "\"a\" 'b'" },
+      "commands": [{
+        "exts": ["py"],
+        "cwd": "dir&<=\"'",
+        "command": "formatter {{vars.flag}} {{vars.quoted}} {{cwd}}",
+        "env": { "VALUE": "a=b&c<d {{vars.quoted}}" },
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    let command = &result.config.commands[0];
+    assert_eq!(command.cwd, PathBuf::from("dir&<=\"'"));
+    assert_eq!(
+      command.arg_templates.render(Path::new("file.py")).unwrap(),
+      vec!["--x=1&y<2", "\"a\" 'b'", "dir&<=\"'"]
+    );
+    assert_eq!(
+      command.env,
+      BTreeMap::from([("VALUE".to_string(), "a=b&c<d \"a\" 'b'".to_string())])
+    );
+  }
+
+  #[test]
+  fn vars_and_env_invalid() {
+    let unresolved_config = parse_config(json!({
+      "vars": [],
+      "commands": [{
+        "exts": ["py"],
+        "vars": { "style": {} },
+        "cwd": "{{cwd}}",
+        "command": "{{vars.missing}} {{file_nme}}",
+        "env": { "NAME": "{{env.DPRINT_PLUGIN_EXEC_MISSING}}" },
+      }],
+    }));
+    run_diagnostics_test(
+      unresolved_config,
+      vec![
+        ConfigurationDiagnostic {
+          property_name: "vars".to_string(),
+          message: "Expected an object.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[0].vars.style".to_string(),
+          message: "Expected a string, number, or boolean value.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[0].cwd".to_string(),
+          message: "Unknown variable 'cwd' in template '{{cwd}}'.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[0].command".to_string(),
+          message: "Unknown variable 'vars.missing' in template '{{vars.missing}}'.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[0].command".to_string(),
+          message: "Unknown variable 'file_nme' in template '{{file_nme}}'.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[0].env.NAME".to_string(),
+          message: concat!(
+            "Unknown variable 'env.DPRINT_PLUGIN_EXEC_MISSING' ",
+            "in template '{{env.DPRINT_PLUGIN_EXEC_MISSING}}'."
+          )
+          .to_string(),
+        },
+      ],
+    );
+  }
+
+  #[test]
+  fn command_split_keeps_template_expressions() {
+    assert_eq!(
+      split_command(
+        "prettier  {{#if use_tabs}}--use-tabs{{/if}} \"a b\" {{split vars.paths sep=\",\"}} c"
+      ),
+      vec![
+        "prettier",
+        "{{#if use_tabs}}--use-tabs{{/if}}",
+        "a b",
+        "{{split vars.paths sep=\",\"}}",
+        "c",
+      ]
+    );
+  }
+
+  #[test]
+  fn cwd_test() {
+    let unresolved_config = parse_config(json!({
+      "cwd": "test-cwd",
+      "commands": [{
+        "command": "1"
+      }, {
+        "cwd": "test-cwd2",
+        "command": "1"
+      }]
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    let config = result.config;
+    assert_eq!(config.commands[0].cwd, PathBuf::from("test-cwd"));
+    assert_eq!(config.commands[1].cwd, PathBuf::from("test-cwd2"));
+  }
+
+  #[test]
+  fn handle_associations_value() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "associations": ["**/*.rs"]
+      }],
+    }));
+    let mut config = Configuration::resolve(unresolved_config, &Default::default()).config;
+    assert!(config.commands.remove(0).associations.is_some());
+
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "associations": []
+      }],
+    }));
+    let mut config = Configuration::resolve(unresolved_config, &Default::default()).config;
+    assert!(config.commands.remove(0).associations.is_none());
+
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "associations": [
+          "**/*.rs",
+          "**/*.json",
+        ]
+      }],
+    }));
+    run_diagnostics_test(
+      unresolved_config,
+      vec![ConfigurationDiagnostic {
+        property_name: "commands[0].associations".to_string(),
+        message: "Unfortunately multiple globs haven't been implemented yet. Please provide a single glob or consider contributing this feature.".to_string(),
+      }],
+    );
+
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "associations": [true]
+      }],
+    }));
+    run_diagnostics_test(
+      unresolved_config,
+      vec![ConfigurationDiagnostic {
+        property_name: "commands[0].associations".to_string(),
+        message: "Expected string value in array.".to_string(),
+      }],
+    );
+
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "associations": true
+      }],
+    }));
+    run_diagnostics_test(
+      unresolved_config,
+      vec![ConfigurationDiagnostic {
+        property_name: "commands[0].associations".to_string(),
+        message: "Expected string or array value.".to_string(),
+      }],
+    );
+  }
+
+  #[test]
+  fn shadowed_commands() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "isort -",
+        "exts": ["py"],
+        "chain": "continue",
+      }, {
+        "command": "black -",
+        "exts": ["py"],
+      }, {
+        "command": "prettier --stdin-filepath {{file_path}}",
+        "associations": "**/*.py",
+      }, {
+        "command": "command",
+        "exts": ["ts", "d.ts"],
+        "fileNames": ["makefile"],
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    assert_eq!(result.config.warnings, vec![]);
+
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["ts"],
+      }, {
+        "command": "command",
+        "exts": ["js", ".js", "JSX"],
+        "fileNames": ["Dockerfile", "makefile"],
+      }, {
+        "command": "command",
+        "exts": ["d.ts", "js"],
+      }, {
+        "command": "command",
+        "fileNames": ["makefile"],
+      }, {
+        "command": "command",
+        "exts": ["ts"],
+        "chain": "continue",
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    // these configurations were accepted before, so they're only warned about
+    assert_eq!(result.diagnostics, vec![]);
+    assert!(result.config.is_valid);
+    assert_eq!(
+      result.config.warnings,
+      vec![
+        ConfigurationDiagnostic {
+          property_name: "commands[1].exts".to_string(),
+          message: "The extension '.js' is specified more than once.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[1].exts".to_string(),
+          message: "The extension '.JSX' never matches because file names are compared in lowercase. Use '.jsx' instead.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[1].fileNames".to_string(),
+          message: "The file name 'Dockerfile' never matches because file names are compared in lowercase. Use 'dockerfile' instead.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[2].exts".to_string(),
+          message: "Never formats any files because they are all matched first by commands[0], commands[1]."
+            .to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[3].fileNames".to_string(),
+          message: "Never formats any files because they are all matched first by commands[1]."
+            .to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[4].exts".to_string(),
+          message: "Never formats any files because they are all matched first by commands[0]."
+            .to_string(),
+        },
+      ],
+    );
+  }
+
+  #[test]
+  fn infers_file_matching_from_associations() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "terraform fmt -",
+        "associations": "**/*.tf",
+      }, {
+        "command": "hadolint -",
+        "associations": "docker/**/{Dockerfile,*.dockerfile}",
+      }, {
+        "command": "command",
+        "associations": "src/*",
+        "exts": ["txt"],
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    let commands = &result.config.commands;
+    assert_eq!(commands[0].file_extensions, vec![".tf"]);
+    assert!(commands[0].file_names.is_empty());
+    assert_eq!(commands[1].file_extensions, vec![".dockerfile"]);
+    assert_eq!(commands[1].file_names, vec!["Dockerfile"]);
+    assert_eq!(commands[2].file_extensions, vec![".txt"]);
+    assert_eq!(result.config.warnings, vec![]);
+
+    for glob in ["**/*", "src/*.[ch]", "**/Dockerfile.*", "{src/*.ts,*.js}"] {
+      let unresolved_config = parse_config(json!({
+        "commands": [{
+          "command": "command",
+          "associations": glob,
+        }],
+      }));
+      let result = Configuration::resolve(unresolved_config, &Default::default());
+      assert_eq!(result.diagnostics, vec![]);
+      let command = &result.config.commands[0];
+      assert!(command.file_extensions.is_empty());
+      assert!(command.file_names.is_empty());
+      assert_eq!(
+        result.config.warnings,
+        vec![ConfigurationDiagnostic {
+          property_name: "commands[0].associations".to_string(),
+          message: format!(
+            concat!(
+              "Cannot infer the file extensions or names matched by '{}', so only the files ",
+              "matched by the plugin's associations in the dprint configuration are formatted. ",
+              "Specify \"exts\" or \"fileNames\" so dprint provides the files to this plugin."
+            ),
+            glob
+          ),
+        }]
+      );
+    }
+  }
+
+  #[test]
+  fn setup_command() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+        "setupCommand": "rustup toolchain install nightly-2025-09-01",
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert!(result.diagnostics.is_empty());
+    let setup = result.config.commands[0].setup_command.as_ref().unwrap();
+    assert_eq!(setup.executable, "rustup");
+    assert_eq!(
+      setup.args,
+      vec!["toolchain", "install", "nightly-2025-09-01"]
+    );
+  }
+
+  #[test]
+  fn setup_command_cwd_and_env() {
+    let unresolved_config = parse_config(json!({
+      "vars": { "toolchain": "nightly" },
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+        "cwd": "project",
+        "env": { "A": "1", "B": "2" },
+        "setupCommand": "rustup toolchain install {{vars.toolchain}} --path={{cwd}}",
+        "setupCwd": "{{vars.toolchain}}",
+        "setupEnv": { "B": "{{vars.toolchain}}" },
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    let setup = result.config.commands[0].setup_command.as_ref().unwrap();
+    assert_eq!(
+      setup.args,
+      vec!["toolchain", "install", "nightly", "--path=project"]
+    );
+    assert_eq!(setup.cwd, PathBuf::from("project/nightly"));
+    assert_eq!(
+      setup.env,
+      BTreeMap::from([
+        ("A".to_string(), "1".to_string()),
+        ("B".to_string(), "nightly".to_string()),
+      ])
+    );
+  }
+
+  #[test]
+  fn setup_timeout_log_and_retry() {
+    let resolve = |setup_retry: &str| {
+      let unresolved_config = parse_config(json!({
+        "commands": [{
+          "command": "command",
+          "exts": ["txt"],
+          "setupCommand": "setup",
+          "setupTimeout": 60,
+          "setupLog": true,
+          "setupRetry": setup_retry,
+          "setupStampDir": ".stamps",
+        }],
+      }));
+      Configuration::resolve(unresolved_config, &Default::default())
+    };
+    for (text, expected) in [
+      ("never", SetupRetry::Never),
+      ("perFile", SetupRetry::PerFile),
+      ("times:3", SetupRetry::Times(3)),
+    ] {
+      let result = resolve(text);
+      assert_eq!(result.diagnostics, vec![]);
+      let setup = result.config.commands[0].setup_command.as_ref().unwrap();
+      assert_eq!(setup.timeout, 60);
+      assert!(setup.log);
+      assert_eq!(setup.retry, expected);
+      assert_eq!(
+        setup.stamp_dir,
+        Some(std::env::current_dir().unwrap().join(".stamps"))
+      );
+    }
+
+    let unresolved_config = parse_config(json!({
+      "timeout": 10,
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+        "setupCommand": "setup",
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    assert_eq!(
+      result.config.commands[0]
+        .setup_command
+        .as_ref()
+        .unwrap()
+        .timeout,
+      10
+    );
+
+    let result = resolve("times:x");
+    assert_eq!(
+      result.diagnostics,
+      vec![ConfigurationDiagnostic {
+        property_name: "commands[0].setupRetry".to_string(),
+        message: "Expected \"never\", \"perFile\", or \"times:N\" (ex. \"times:3\").".to_string(),
+      }]
+    );
+  }
+
+  #[test]
+  fn before_all_and_after_all() {
+    let unresolved_config = parse_config(json!({
+      "beforeAll": "daemon start --indent={{indent_width}}",
+      "afterAll": "daemon stop",
+      "hookTimeout": 60,
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    let before_all = result.config.before_all.unwrap();
+    assert_eq!(before_all.executable, "daemon");
+    assert_eq!(before_all.args, vec!["start", "--indent=2"]);
+    assert_eq!(before_all.cwd, std::env::current_dir().unwrap());
+    assert_eq!(before_all.timeout, 60);
+    assert!(!before_all.log);
+    let after_all = result.config.after_all.unwrap();
+    assert_eq!(after_all.args, vec!["stop"]);
+    assert_eq!(after_all.timeout, 60);
+
+    let unresolved_config = parse_config(json!({
+      "timeout": 10,
+      "afterAll": "daemon stop",
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    assert_eq!(result.config.after_all.unwrap().timeout, 10);
+
+    let unresolved_config = parse_config(json!({
+      "hookTimeout": 60,
+      "hookLog": true,
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+      }],
+    }));
+    run_diagnostics_test(
+      unresolved_config,
+      vec![
+        ConfigurationDiagnostic {
+          property_name: "hookTimeout".to_string(),
+          message: "Only supported when \"beforeAll\" or \"afterAll\" is set.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "hookLog".to_string(),
+          message: "Only supported when \"beforeAll\" or \"afterAll\" is set.".to_string(),
+        },
+      ],
+    );
+  }
+
+  #[test]
+  fn setup_retry_allows_retry() {
+    assert!(!SetupRetry::Never.allows_retry(1));
+    assert!(SetupRetry::PerFile.allows_retry(100));
+    assert!(SetupRetry::Times(2).allows_retry(2));
+    assert!(!SetupRetry::Times(2).allows_retry(3));
+  }
+
+  #[test]
+  fn setup_cwd_and_env_without_setup_command() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+        "setupCwd": "dir",
+        "setupEnv": { "A": "1" },
+      }],
+    }));
+    run_diagnostics_test(
+      unresolved_config,
+      vec![
+        ConfigurationDiagnostic {
+          property_name: "commands[0].setupCwd".to_string(),
+          message: "Only supported when \"setupCommand\" is set.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[0].setupEnv".to_string(),
+          message: "Only supported when \"setupCommand\" is set.".to_string(),
+        },
+      ],
+    );
+  }
+
+  #[test]
+  fn setup_command_empty() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+        "setupCommand": "",
+      }],
+    }));
+    run_diagnostics_test(
+      unresolved_config,
+      vec![ConfigurationDiagnostic {
+        property_name: "commands[0].setupCommand".to_string(),
+        message: "Expected to find a command name.".to_string(),
+      }],
+    );
+  }
+
+  #[test]
+  fn regions() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["go"],
+        "regions": {
+          "start": "-- fmt:sql$",
+          "end": "^\\s*-- end",
+        },
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert!(result.diagnostics.is_empty());
+    let regions = result.config.commands[0].regions.as_ref().unwrap();
+    assert_eq!(regions.start.as_str(), "-- fmt:sql$");
+    assert_eq!(regions.end.as_str(), "^\\s*-- end");
+  }
+
+  #[test]
+  fn regions_invalid() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["go"],
+        "stdin": false,
+        "regions": {
+          "start": "(",
+          "other": 1,
+        },
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert!(!result.config.is_valid);
+    let property_names = result
+      .diagnostics
+      .iter()
+      .map(|d| d.property_name.as_str())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      property_names,
+      vec![
+        "commands[0].regions.start",
+        "commands[0].regions.end",
+        "commands[0].regions.other",
+      ]
+    );
+    assert!(
+      result.diagnostics[0]
+        .message
+        .starts_with("Invalid regular expression:")
+    );
+  }
+
+  #[test]
+  fn notebook_input() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "black -",
+        "exts": ["ipynb"],
+        "input": "notebook",
+      }, {
+        "command": "air format -",
+        "exts": ["ipynb"],
+        "input": "notebook",
+        "notebookLanguage": "R",
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert!(result.diagnostics.is_empty());
+    let languages = result
+      .config
+      .commands
+      .iter()
+      .map(|command| match &command.input {
+        CommandInput::Notebook { language } => language.as_str(),
+        CommandInput::File => unreachable!(),
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(languages, vec!["python", "R"]);
+  }
+
+  #[test]
+  fn notebook_input_invalid() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["ipynb"],
+        "input": "other",
+        "notebookLanguage": "python",
+      }, {
+        "command": "command",
+        "exts": ["ipynb"],
+        "input": "notebook",
+        "stdin": false,
+      }],
+    }));
+    run_diagnostics_test(
+      unresolved_config,
+      vec![
+        ConfigurationDiagnostic {
+          property_name: "commands[0].input".to_string(),
+          message: "Unknown input 'other'. Expected \"file\" or \"notebook\".".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[1].input".to_string(),
+          message: "Notebooks can only be formatted when \"stdin\" is true.".to_string(),
+        },
+      ],
+    );
+  }
+
+  #[test]
+  fn lsp_type() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "server --stdio",
+        "type": "lsp",
+        "lspLanguageId": "mylang",
+        "exts": ["my"],
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    assert!(matches!(
+      &result.config.commands[0].command_type,
+      CommandType::Lsp { language_id: Some(id) } if id == "mylang"
+    ));
+    assert!(matches!(
+      result.config.commands[1].command_type,
+      CommandType::Exec
+    ));
+  }
+
+  #[test]
+  fn lsp_type_invalid() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+        "type": "other",
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "lspLanguageId": "mylang",
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "type": "lsp",
+        "stdin": false,
+      }],
+    }));
+    run_diagnostics_test(
+      unresolved_config,
+      vec![
+        ConfigurationDiagnostic {
+          property_name: "commands[0].type".to_string(),
+          message: "Unknown type 'other'. Expected \"exec\" or \"lsp\".".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[1].lspLanguageId".to_string(),
+          message: "Only supported when \"type\" is \"lsp\".".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[2].stdin".to_string(),
+          message: "Language servers are always provided the file text, so \"stdin\" must be true."
+            .to_string(),
+        },
+      ],
+    );
+  }
+
+  #[test]
+  fn output() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "black --diff -",
+        "exts": ["py"],
+        "output": "unifiedDiff",
+      }, {
+        "command": "clang-format --output-replacements-xml",
+        "exts": ["c"],
+        "output": "xmlReplacements",
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    let outputs = result
+      .config
+      .commands
+      .iter()
+      .map(|c| c.output)
+      .collect::<Vec<_>>();
+    assert_eq!(
+      outputs,
+      vec![
+        CommandOutput::UnifiedDiff,
+        CommandOutput::XmlReplacements,
+        CommandOutput::Text
+      ]
+    );
+
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+        "output": "diff",
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "type": "lsp",
+        "output": "unifiedDiff",
+      }],
+    }));
+    run_diagnostics_test(
+      unresolved_config,
+      vec![
+        ConfigurationDiagnostic {
+          property_name: "commands[0].output".to_string(),
+          message:
+            "Unknown output 'diff'. Expected \"text\", \"unifiedDiff\", \"xmlReplacements\", or \"json\"."
+              .to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[1].output".to_string(),
+          message: "Not supported when \"type\" is \"lsp\".".to_string(),
+        },
+      ],
+    );
+  }
+
+  #[test]
+  fn empty_output_and_fail_on_stderr() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+        "emptyOutput": "unchanged",
+        "failOnStderr": true,
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "emptyOutput": "error",
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    let commands = &result.config.commands;
+    assert_eq!(
+      commands.iter().map(|c| c.empty_output).collect::<Vec<_>>(),
+      vec![
+        EmptyOutput::Unchanged,
+        EmptyOutput::Error,
+        EmptyOutput::Empty
+      ]
+    );
+    assert_eq!(
+      commands
+        .iter()
+        .map(|c| c.fail_on_stderr)
+        .collect::<Vec<_>>(),
+      vec![true, false, false]
+    );
+
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+        "emptyOutput": "none",
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "output": "unifiedDiff",
+        "emptyOutput": "unchanged",
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "type": "lsp",
+        "failOnStderr": true,
+      }],
+    }));
+    run_diagnostics_test(
+      unresolved_config,
+      vec![
+        ConfigurationDiagnostic {
+          property_name: "commands[0].emptyOutput".to_string(),
+          message: "Unknown empty output 'none'. Expected \"unchanged\", \"empty\", or \"error\"."
+            .to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[1].emptyOutput".to_string(),
+          message: "Only supported when \"output\" is \"text\".".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[2].failOnStderr".to_string(),
+          message: "Not supported when \"type\" is \"lsp\".".to_string(),
+        },
+      ],
+    );
+  }
+
+  #[test]
+  fn chain() {
     let unresolved_config = parse_config(json!({
-      "cacheKey": "2",
-      "timeout": 5
+      "commands": [{
+        "command": "isort -",
+        "exts": ["py"],
+        "chain": "continue",
+      }, {
+        "command": "black -",
+        "exts": ["py"],
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "chain": "stop",
+      }],
     }));
     let result = Configuration::resolve(unresolved_config, &Default::default());
-    let config = result.config;
-    assert_eq!(config.line_width, 120);
-    assert_eq!(config.indent_width, 2);
-    assert!(!config.use_tabs);
-    assert_eq!(config.cache_key, "2");
-    assert_eq!(config.timeout, 5);
-    assert_eq!(result.diagnostics, vec![ConfigurationDiagnostic {
-      property_name: "commands".to_string(),
-      message: "Expected to find a \"commands\" array property (see https://github.com/dprint/dprint-plugin-exec for instructions)".to_string(),
-    }]);
-  }
+    assert_eq!(result.diagnostics, vec![]);
+    let commands = &result.config.commands;
+    assert_eq!(
+      commands.iter().map(|c| c.chain).collect::<Vec<_>>(),
+      vec![Some(CommandChain::Continue), None, Some(CommandChain::Stop)]
+    );
+    assert_eq!(
+      commands.iter().map(|c| c.stops_chain()).collect::<Vec<_>>(),
+      vec![false, true, true]
+    );
 
-  #[test]
-  fn empty_command_name() {
-    let config = parse_config(json!({
+    let unresolved_config = parse_config(json!({
       "commands": [{
-        "command": "",
+        "command": "command",
+        "exts": ["txt"],
+        "chain": "next",
       }],
     }));
     run_diagnostics_test(
-      config,
+      unresolved_config,
       vec![ConfigurationDiagnostic {
-        property_name: "commands[0].command".to_string(),
-        message: "Expected to find a command name.".to_string(),
+        property_name: "commands[0].chain".to_string(),
+        message: "Unknown chain 'next'. Expected \"continue\" or \"stop\".".to_string(),
       }],
-    )
+    );
   }
 
   #[test]
-  fn cwd_test() {
+  fn check_mode() {
     let unresolved_config = parse_config(json!({
-      "cwd": "test-cwd",
       "commands": [{
-        "command": "1"
+        "command": "shellcheck -",
+        "exts": ["sh"],
+        "mode": "check",
       }, {
-        "cwd": "test-cwd2",
-        "command": "1"
-      }]
+        "command": "command",
+        "exts": ["txt"],
+      }],
     }));
     let result = Configuration::resolve(unresolved_config, &Default::default());
-    let config = result.config;
-    assert_eq!(config.commands[0].cwd, PathBuf::from("test-cwd"));
-    assert_eq!(config.commands[1].cwd, PathBuf::from("test-cwd2"));
-  }
+    assert_eq!(result.diagnostics, vec![]);
+    assert_eq!(
+      result
+        .config
+        .commands
+        .iter()
+        .map(|c| c.mode)
+        .collect::<Vec<_>>(),
+      vec![CommandMode::Check, CommandMode::Format]
+    );
 
-  #[test]
-  fn handle_associations_value() {
     let unresolved_config = parse_config(json!({
       "commands": [{
         "command": "command",
-        "associations": ["**/*.rs"]
+        "exts": ["txt"],
+        "mode": "lint",
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "mode": "check",
+        "output": "unifiedDiff",
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "mode": "check",
+        "emptyOutput": "unchanged",
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "type": "lsp",
+        "mode": "check",
       }],
     }));
-    let mut config = Configuration::resolve(unresolved_config, &Default::default()).config;
-    assert!(config.commands.remove(0).associations.is_some());
+    run_diagnostics_test(
+      unresolved_config,
+      vec![
+        ConfigurationDiagnostic {
+          property_name: "commands[0].mode".to_string(),
+          message: "Unknown mode 'lint'. Expected \"format\" or \"check\".".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[1].output".to_string(),
+          message: "Not supported when \"mode\" is \"check\".".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[2].emptyOutput".to_string(),
+          message: "Not supported when \"mode\" is \"check\".".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[3].mode".to_string(),
+          message: "Not supported when \"type\" is \"lsp\".".to_string(),
+        },
+      ],
+    );
+  }
 
+  #[test]
+  fn transport() {
     let unresolved_config = parse_config(json!({
       "commands": [{
         "command": "command",
-        "associations": []
+        "exts": ["txt"],
+        "transport": { "unixSocket": "{{vars.dir}}/formatter.sock" },
+        "vars": { "dir": "run" },
       }],
     }));
-    let mut config = Configuration::resolve(unresolved_config, &Default::default()).config;
-    assert!(config.commands.remove(0).associations.is_none());
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert_eq!(result.diagnostics, vec![]);
+    assert_eq!(
+      result.config.commands[0]
+        .transport
+        .as_ref()
+        .unwrap()
+        .unix_socket,
+      std::env::current_dir().unwrap().join("run/formatter.sock")
+    );
 
     let unresolved_config = parse_config(json!({
       "commands": [{
         "command": "command",
-        "associations": [
-          "**/*.rs",
-          "**/*.json",
-        ]
+        "exts": ["txt"],
+        "transport": "socket",
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "transport": { "other": 1 },
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "type": "lsp",
+        "transport": { "unixSocket": "formatter.sock" },
       }],
     }));
     run_diagnostics_test(
       unresolved_config,
-      vec![ConfigurationDiagnostic {
-        property_name: "commands[0].associations".to_string(),
-        message: "Unfortunately multiple globs haven't been implemented yet. Please provide a single glob or consider contributing this feature.".to_string(),
+      vec![
+        ConfigurationDiagnostic {
+          property_name: "commands[0].transport".to_string(),
+          message: "Expected an object with a \"unixSocket\" property.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[1].transport.unixSocket".to_string(),
+          message: "Expected the path of a Unix socket.".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[1].transport.other".to_string(),
+          message: "Unknown property in configuration".to_string(),
+        },
+        ConfigurationDiagnostic {
+          property_name: "commands[2].transport".to_string(),
+          message: "Not supported when \"type\" is \"lsp\".".to_string(),
+        },
+      ],
+    );
+  }
+
+  #[test]
+  fn protect() {
+    let unresolved_config = parse_config(json!({
+      "frontMatter": true,
+      "ignoreBlocks": true,
+      "ignoreStartMarker": "fmt: off",
+      "ignoreEndMarker": "fmt: on",
+      "commands": [{
+        "command": "command",
+        "exts": ["md"],
+      }, {
+        "command": "command",
+        "exts": ["py"],
+        "frontMatter": false,
+        "ignoreBlocks": false,
+      }, {
+        "command": "command",
+        "exts": ["txt"],
+        "ignoreEndMarker": "end",
       }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert!(result.diagnostics.is_empty());
+    let commands = result.config.commands;
+    assert!(commands[0].protect.front_matter);
+    assert!(commands[0].protect.ignore_blocks);
+    assert_eq!(
+      commands[0].protect.ignore_markers,
+      ("fmt: off".to_string(), "fmt: on".to_string())
+    );
+    assert!(!commands[1].protect.front_matter);
+    assert!(!commands[1].protect.ignore_blocks);
+    assert!(commands[2].protect.ignore_blocks);
+    assert_eq!(
+      commands[2].protect.ignore_markers,
+      ("fmt: off".to_string(), "end".to_string())
     );
 
     let unresolved_config = parse_config(json!({
+      "ignoreEndMarker": "",
       "commands": [{
         "command": "command",
-        "associations": [true]
+        "exts": ["md"],
       }],
     }));
     run_diagnostics_test(
       unresolved_config,
       vec![ConfigurationDiagnostic {
-        property_name: "commands[0].associations".to_string(),
-        message: "Expected string value in array.".to_string(),
+        property_name: "ignoreEndMarker".to_string(),
+        message: "Expected a non-empty marker.".to_string(),
       }],
     );
+  }
 
+  #[test]
+  fn protect_default() {
     let unresolved_config = parse_config(json!({
       "commands": [{
         "command": "command",
-        "associations": true
+        "exts": ["md"],
       }],
     }));
-    run_diagnostics_test(
-      unresolved_config,
-      vec![ConfigurationDiagnostic {
-        property_name: "commands[0].associations".to_string(),
-        message: "Expected string or array value.".to_string(),
-      }],
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    let protect = &result.config.commands[0].protect;
+    assert!(!protect.front_matter);
+    // opt-in so formatters that rewrite comments don't receive placeholder lines
+    assert!(!protect.ignore_blocks);
+    assert_eq!(
+      protect.ignore_markers,
+      (
+        "dprint-ignore-start".to_string(),
+        "dprint-ignore-end".to_string()
+      )
     );
   }
 
   #[test]
-  fn setup_command() {
+  fn skip() {
     let unresolved_config = parse_config(json!({
+      "skipGeneratedMarkers": ["@generated"],
+      "maxFileSize": 1000,
+      "logSkipped": true,
       "commands": [{
+        "command": "command",
+        "exts": ["go"],
+        "skipGeneratedMarkers": "DO NOT EDIT",
+        "skipBinary": true,
+      }, {
         "command": "command",
         "exts": ["txt"],
-        "setupCommand": "rustup toolchain install nightly-2025-09-01",
+        "maxFileSize": 5,
       }],
     }));
     let result = Configuration::resolve(unresolved_config, &Default::default());
     assert!(result.diagnostics.is_empty());
-    let setup = result.config.commands[0].setup_command.as_ref().unwrap();
-    assert_eq!(setup.executable, "rustup");
+    assert!(result.config.log_skipped);
+    let commands = result.config.commands;
+    assert_eq!(commands[0].skip.generated_markers, vec!["DO NOT EDIT"]);
+    assert!(commands[0].skip.binary);
+    assert_eq!(commands[0].skip.max_file_size, Some(1000));
+    assert_eq!(commands[1].skip.generated_markers, vec!["@generated"]);
+    assert!(!commands[1].skip.binary);
+    assert_eq!(commands[1].skip.max_file_size, Some(5));
+  }
+
+  #[test]
+  fn skip_invalid() {
+    let unresolved_config = parse_config(json!({
+      "commands": [{
+        "command": "command",
+        "exts": ["go"],
+        "skipGeneratedMarkers": [""],
+        "maxFileSize": -1,
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    let property_names = result
+      .diagnostics
+      .iter()
+      .map(|d| d.property_name.as_str())
+      .collect::<Vec<_>>();
     assert_eq!(
-      setup.args,
-      vec!["toolchain", "install", "nightly-2025-09-01"]
+      property_names,
+      vec![
+        "commands[0].skipGeneratedMarkers",
+        "commands[0].maxFileSize",
+      ]
     );
   }
 
   #[test]
-  fn setup_command_empty() {
+  fn memoize() {
     let unresolved_config = parse_config(json!({
+      "cwd": "test-cwd",
+      "memoize": true,
+      "memoizeDir": ".cache/exec",
+      "commands": [{
+        "command": "command",
+        "exts": ["txt"],
+      }],
+    }));
+    let result = Configuration::resolve(unresolved_config, &Default::default());
+    assert!(result.diagnostics.is_empty());
+    assert!(result.config.memoize);
+    assert_eq!(
+      result.config.memoize_dir,
+      Some(PathBuf::from("test-cwd").join(".cache/exec"))
+    );
+
+    let unresolved_config = parse_config(json!({
+      "memoizeDir": ".cache/exec",
       "commands": [{
         "command": "command",
         "exts": ["txt"],
-        "setupCommand": "",
       }],
     }));
     run_diagnostics_test(
       unresolved_config,
       vec![ConfigurationDiagnostic {
-        property_name: "commands[0].setupCommand".to_string(),
-        message: "Expected to find a command name.".to_string(),
+        property_name: "memoizeDir".to_string(),
+        message: "Only supported when \"memoize\" is true.".to_string(),
       }],
     );
   }
@@ -717,6 +3040,167 @@ mod tests {
       );
     }
 
+    #[test]
+    fn command_cache_key_binary_file() {
+      let unresolved_config = parse_config(json!({
+        "commands": [{
+          "exts": ["txt"],
+          "command": "1",
+          "cacheKeyFiles": ["./tests/resources/binary.bin"]
+        }],
+      }));
+      let result = Configuration::resolve(unresolved_config, &Default::default());
+      assert_eq!(result.diagnostics, vec![]);
+      assert!(result.config.is_valid);
+      assert_ne!(result.config.cache_key, "0");
+    }
+
+    #[test]
+    fn command_cache_key_optional_missing_file() {
+      let unresolved_config = parse_config(json!({
+        "commands": [{
+          "exts": ["txt"],
+          "command": "1",
+          "cacheKeyFiles": [
+            "./tests/resources/one-line.txt",
+            { "path": "path/to/missing/file", "optional": true },
+            { "path": "path/to/missing/**/*.toml", "optional": true },
+          ]
+        }],
+      }));
+      let result = Configuration::resolve(unresolved_config, &Default::default());
+      assert!(result.config.is_valid);
+      assert_eq!(result.diagnostics, vec![]);
+      assert_ne!(result.config.cache_key, "0");
+    }
+
+    #[test]
+    fn command_cache_key_glob_and_directory() {
+      let resolve = |cache_key_files: serde_json::Value| {
+        let unresolved_config = parse_config(json!({
+          "commands": [{
+            "exts": ["txt"],
+            "command": "1",
+            "cacheKeyFiles": cache_key_files,
+          }],
+        }));
+        let result = Configuration::resolve(unresolved_config, &Default::default());
+        assert_eq!(result.diagnostics, vec![]);
+        result.config.cache_key
+      };
+      let glob_key = resolve(json!(["tests/resources/**"]));
+      assert_eq!(glob_key, resolve(json!(["./tests/resources"])));
+      assert_ne!(glob_key, resolve(json!(["tests/resources/*.txt"])));
+    }
+
+    #[test]
+    fn command_cache_key_command() {
+      let resolve = |version: &str| {
+        let unresolved_config = parse_config(json!({
+          "cacheKey": "99",
+          "commands": [{
+            "exts": ["txt"],
+            "command": "1",
+            "cacheKeyCommand": format!("deno eval \"console.log('{}')\"", version),
+          }],
+        }));
+        let result = Configuration::resolve(unresolved_config, &Default::default());
+        assert!(result.config.is_valid);
+        assert_eq!(result.diagnostics, vec![]);
+        result.config.cache_key
+      };
+      let cache_key = resolve("1.0.0");
+      assert!(cache_key.starts_with("99"));
+      assert_eq!(cache_key, resolve("1.0.0"));
+      assert_ne!(cache_key, resolve("1.0.1"));
+    }
+
+    #[test]
+    fn command_cache_key_command_fails() {
+      let unresolved_config = parse_config(json!({
+        "commands": [{
+          "exts": ["txt"],
+          "command": "1",
+          "cacheKeyCommand": "deno eval \"Deno.exit(1)\"",
+        }],
+      }));
+      let result = Configuration::resolve(unresolved_config, &Default::default());
+      assert!(!result.config.is_valid);
+      assert_eq!(result.diagnostics.len(), 1);
+      assert_eq!(
+        result.diagnostics[0].property_name,
+        "commands[0].cacheKeyCommand"
+      );
+      assert!(
+        result.diagnostics[0]
+          .message
+          .starts_with("'deno' exited with")
+      );
+    }
+
+    #[test]
+    fn command_cache_key_executable() {
+      let resolve = |command: &str, cache_key_executable: bool| {
+        let unresolved_config = parse_config(json!({
+          "commands": [{
+            "exts": ["txt"],
+            "command": command,
+            "cacheKeyExecutable": cache_key_executable,
+          }],
+        }));
+        Configuration::resolve(unresolved_config, &Default::default())
+      };
+      let result = resolve("deno run -A ./tests/fold.ts", true);
+      assert_eq!(result.diagnostics, vec![]);
+      assert_ne!(
+        result.config.cache_key,
+        resolve("deno run -A ./tests/fold.ts", false)
+          .config
+          .cache_key
+      );
+
+      let result = resolve("missing-executable", true);
+      assert_eq!(
+        result.diagnostics,
+        vec![ConfigurationDiagnostic {
+          property_name: "commands[0].cacheKeyExecutable".to_string(),
+          message: "Unable to find executable 'missing-executable' on the PATH.".to_string(),
+        }]
+      );
+    }
+
+    #[test]
+    fn command_cache_key_invalid_entries() {
+      let unresolved_config = parse_config(json!({
+        "commands": [{
+          "exts": ["txt"],
+          "command": "1",
+          "cacheKeyFiles": [
+            1,
+            { "optional": true },
+            { "path": "file.txt", "other": true },
+          ]
+        }],
+      }));
+      run_diagnostics_test(
+        unresolved_config,
+        vec![
+          ConfigurationDiagnostic {
+            property_name: "commands[0].cacheKeyFiles[0]".to_string(),
+            message: "Expected string or object element.".to_string(),
+          },
+          ConfigurationDiagnostic {
+            property_name: "commands[0].cacheKeyFiles[1].path".to_string(),
+            message: "Expected to find a path.".to_string(),
+          },
+          ConfigurationDiagnostic {
+            property_name: "commands[0].cacheKeyFiles[2].other".to_string(),
+            message: "Unknown property in configuration".to_string(),
+          },
+        ],
+      );
+    }
+
     #[test]
     fn command_cache_key_one_command_one_file() {
       let unresolved_config = parse_config(json!({
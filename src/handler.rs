@@ -1,9 +1,11 @@
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::BufRead;
 use std::io::Write;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::ops::Range;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
@@ -29,19 +31,47 @@ use dprint_core::plugins::FormatResult;
 use dprint_core::plugins::HostFormatRequest;
 use dprint_core::plugins::PluginInfo;
 use dprint_core::plugins::PluginResolveConfigurationResult;
-use handlebars::Handlebars;
-use serde::Deserialize;
-use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
 use tokio::sync::OnceCell;
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::Receiver;
 use tokio::sync::oneshot::Sender;
 
 use crate::configuration::CommandConfiguration;
+use crate::configuration::CommandInput;
+use crate::configuration::CommandMode;
+use crate::configuration::CommandOutput;
+use crate::configuration::CommandType;
 use crate::configuration::Configuration;
+use crate::configuration::EmptyOutput;
+use crate::configuration::RegionsConfiguration;
 use crate::configuration::SetupCommand;
+use crate::configuration::SkipConfiguration;
+use crate::configuration::TransportConfiguration;
+use crate::hooks::AfterAllHooks;
+use crate::json_output::JsonDiagnostic;
+use crate::json_output::JsonOutput;
+use crate::json_output::render_diagnostics;
+use crate::lsp::LspDocument;
+use crate::lsp::LspServers;
+use crate::memoize::ResultCache;
+use crate::memoize::compute_result_key;
+use crate::notebook::Notebook;
+use crate::notebook::mask_magics;
+use crate::notebook::replace_sources;
+use crate::notebook::unmask_magics;
+use crate::patch::apply_unified_diff;
+use crate::patch::apply_xml_replacements;
+use crate::protect::ProtectedText;
+use crate::regions::dedent;
+use crate::regions::find_regions;
+use crate::regions::reindent;
+use crate::transport::SocketRequestMetadata;
+use crate::transport::SocketResponse;
+use crate::transport::send_socket_request;
 
-struct ChildKillOnDrop(std::process::Child);
+pub(crate) struct ChildKillOnDrop(pub(crate) std::process::Child);
 
 impl Drop for ChildKillOnDrop {
   fn drop(&mut self) {
@@ -68,6 +98,17 @@ pub struct ExecHandler {
   /// Tracks setup commands that have already run so they only run once
   /// for the lifetime of the process, even while formatting in parallel.
   setup_state: SetupState,
+  /// Remembers command outputs when the configuration enables `memoize`.
+  result_cache: ResultCache,
+  /// Language servers of `lsp` commands, which are kept running across files.
+  lsp_servers: LspServers,
+}
+
+impl ExecHandler {
+  /// The `afterAll` commands to run when the process shuts down.
+  pub fn after_all_hooks(&self) -> AfterAllHooks {
+    self.setup_state.after_all.clone()
+  }
 }
 
 #[async_trait(?Send)]
@@ -104,6 +145,12 @@ impl AsyncPluginHandler for ExecHandler {
   ) -> PluginResolveConfigurationResult<Configuration> {
     let result = Configuration::resolve(config, &global_config);
     let config = result.config;
+    for warning in &config.warnings {
+      eprintln!(
+        "[dprint-plugin-exec] Warning in configuration property \"{}\": {}",
+        warning.property_name, warning.message
+      );
+    }
     PluginResolveConfigurationResult {
       file_matching: FileMatchingInfo {
         file_extensions: config
@@ -129,22 +176,87 @@ impl AsyncPluginHandler for ExecHandler {
     request: FormatRequest<Self::Configuration>,
     _format_with_host: impl FnMut(HostFormatRequest) -> LocalBoxFuture<'static, FormatResult> + 'static,
   ) -> FormatResult {
-    if request.range.is_some() {
-      // we don't support range formatting for this plugin
-      return Ok(None);
+    if let Some(range) = request.range {
+      return self
+        .format_range(
+          request.file_path,
+          request.file_bytes,
+          range,
+          request.config,
+          request.token,
+        )
+        .await;
     }
 
-    format_bytes(
+    format_bytes_with_caches(
       request.file_path,
       request.file_bytes,
       request.config,
       request.token.clone(),
       &self.setup_state,
+      &self.result_cache,
+      &self.lsp_servers,
     )
     .await
   }
 }
 
+impl ExecHandler {
+  /// Formats a range of the file, which is only supported when the file is
+  /// formatted by a single `lsp` command.
+  async fn format_range(
+    &self,
+    file_path: PathBuf,
+    file_bytes: Vec<u8>,
+    range: Range<usize>,
+    config: Arc<Configuration>,
+    token: Arc<dyn CancellationToken>,
+  ) -> FormatResult {
+    let commands = select_commands(&config, &file_path)?;
+    let [command] = commands.as_slice() else {
+      return Ok(None);
+    };
+    if !matches!(command.command_type, CommandType::Lsp { .. }) {
+      return Ok(None);
+    }
+    let Ok(text) = std::str::from_utf8(&file_bytes) else {
+      return Ok(None);
+    };
+    match prepare_command(
+      &self.setup_state,
+      &config,
+      command,
+      &file_path,
+      &file_bytes,
+      &token,
+    )
+    .await?
+    {
+      CommandPreparation::Ready => {}
+      CommandPreparation::Skipped | CommandPreparation::Cancelled => return Ok(None),
+    }
+    // the range can't be mapped onto notebook cells, regions or protected text,
+    // so the whole file is formatted for those
+    let range = (matches!(command.input, CommandInput::File)
+      && command.regions.is_none()
+      && !(command.stdin && ProtectedText::new(text, &command.protect).is_some()))
+    .then_some(range);
+    let ctx = CommandContext {
+      file_path: &file_path,
+      config: &config,
+      command,
+      range,
+      token: &token,
+      result_cache: &self.result_cache,
+      lsp_servers: &self.lsp_servers,
+    };
+    match format_with_command(&ctx, file_bytes.clone()).await? {
+      Some(formatted) => check_formatted(&file_bytes, formatted),
+      None => Ok(None),
+    }
+  }
+}
+
 pub async fn format_bytes(
   file_path: PathBuf,
   original_file_bytes: Vec<u8>,
@@ -152,6 +264,80 @@ pub async fn format_bytes(
   token: Arc<dyn CancellationToken>,
   setup_state: &SetupState,
 ) -> FormatResult {
+  format_bytes_with_caches(
+    file_path,
+    original_file_bytes,
+    config,
+    token,
+    setup_state,
+    &ResultCache::default(),
+    &LspServers::default(),
+  )
+  .await
+}
+
+/// Formats the bytes while reusing the memoized results and language servers
+/// of previous formats.
+pub(crate) async fn format_bytes_with_caches(
+  file_path: PathBuf,
+  original_file_bytes: Vec<u8>,
+  config: Arc<Configuration>,
+  token: Arc<dyn CancellationToken>,
+  setup_state: &SetupState,
+  result_cache: &ResultCache,
+  lsp_servers: &LspServers,
+) -> FormatResult {
+  let mut file_bytes: Cow<[u8]> = Cow::Borrowed(&original_file_bytes);
+  for command in select_commands(&config, &file_path)? {
+    match prepare_command(
+      setup_state,
+      &config,
+      command,
+      &file_path,
+      &file_bytes,
+      &token,
+    )
+    .await?
+    {
+      CommandPreparation::Ready => {}
+      CommandPreparation::Skipped => continue,
+      CommandPreparation::Cancelled => return Ok(None),
+    }
+
+    let ctx = CommandContext {
+      file_path: &file_path,
+      config: &config,
+      command,
+      range: None,
+      token: &token,
+      result_cache,
+      lsp_servers,
+    };
+    match format_with_command(&ctx, file_bytes.into_owned()).await? {
+      Some(bytes) => file_bytes = Cow::Owned(bytes),
+      // return back the original text when cancelled
+      None => return Ok(None),
+    }
+  }
+
+  check_formatted(&original_file_bytes, file_bytes.into_owned())
+}
+
+/// Formats the file's bytes with the command, returning `None` when the
+/// format was cancelled.
+async fn format_with_command(
+  ctx: &CommandContext<'_>,
+  file_bytes: Vec<u8>,
+) -> Result<Option<Vec<u8>>> {
+  match &ctx.command.input {
+    CommandInput::Notebook { language } => format_notebook(ctx, file_bytes, language).await,
+    CommandInput::File => format_protected(ctx, file_bytes).await,
+  }
+}
+
+/// Gets the result of formatting the file, erroring when the formatted text
+/// is suspiciously empty.
+fn check_formatted(original_file_bytes: &[u8], file_bytes: Vec<u8>) -> FormatResult {
   fn trim_bytes_len(bytes: &[u8]) -> usize {
     let mut start = 0;
     let mut end = bytes.len();
@@ -168,131 +354,579 @@ pub async fn format_bytes(
       end -= 1;
     }
 
-    if end < start { 0 } else { end - start }
+    end.saturating_sub(start)
   }
 
-  let mut file_bytes: Cow<Vec<u8>> = Cow::Borrowed(&original_file_bytes);
-  for command in select_commands(&config, &file_path)? {
-    // run the command's setup once before formatting with it for the first time
-    if let Some(setup_command) = &command.setup_command {
-      match setup_state
-        .run_once(&command.cwd, setup_command, &token)
+  const MIN_CHARS_TO_EMPTY: usize = 100;
+  Ok(if file_bytes == original_file_bytes {
+    None
+  } else if trim_bytes_len(original_file_bytes) > MIN_CHARS_TO_EMPTY
+    && trim_bytes_len(&file_bytes) == 0
+  {
+    // prevent someone formatting all their files to empty files
+    bail!(
+      concat!(
+        "The original file text was greater than {} characters, but the formatted text was empty. ",
+        "Perhaps dprint-plugin-exec has been misconfigured?",
+      ),
+      MIN_CHARS_TO_EMPTY
+    )
+  } else {
+    Some(file_bytes)
+  })
+}
+
+/// Registers the `afterAll` hook and runs the `beforeAll` hook once before
+/// the first command formats a file.
+async fn run_hooks(
+  setup_state: &SetupState,
+  config: &Configuration,
+  token: &Arc<dyn CancellationToken>,
+) -> Result<SetupRun> {
+  if let Some(after_all) = &config.after_all {
+    setup_state.after_all.register(after_all);
+  }
+  match &config.before_all {
+    Some(before_all) => {
+      setup_state
+        .run_once(before_all, &config.cache_key, token)
+        .await
+    }
+    None => Ok(SetupRun::Completed),
+  }
+}
+
+enum CommandPreparation {
+  Ready,
+  /// The command doesn't format the file (see `SkipConfiguration`).
+  Skipped,
+  Cancelled,
+}
+
+/// Checks whether the command skips the file and otherwise runs the hooks
+/// and the command's setup once before formatting with it for the first time.
+async fn prepare_command(
+  setup_state: &SetupState,
+  config: &Configuration,
+  command: &CommandConfiguration,
+  file_path: &Path,
+  file_bytes: &[u8],
+  token: &Arc<dyn CancellationToken>,
+) -> Result<CommandPreparation> {
+  if let Some(reason) = get_skip_reason(&command.skip, file_bytes) {
+    if config.log_skipped {
+      eprintln!(
+        "[dprint-plugin-exec] Skipped formatting '{}' with '{}' because {}.",
+        file_path.display(),
+        command.executable,
+        reason
+      );
+    }
+    return Ok(CommandPreparation::Skipped);
+  }
+  // only after the skip check so skipped files don't start the hooks
+  match run_hooks(setup_state, config, token).await? {
+    SetupRun::Completed => {}
+    SetupRun::Cancelled => return Ok(CommandPreparation::Cancelled),
+  }
+  if let Some(setup_command) = &command.setup_command {
+    match setup_state
+      .run_once(setup_command, &config.cache_key, token)
+      .await?
+    {
+      SetupRun::Completed => {}
+      SetupRun::Cancelled => return Ok(CommandPreparation::Cancelled),
+    }
+  }
+  Ok(CommandPreparation::Ready)
+}
+
+/// What's needed to format a file with a command.
+struct CommandContext<'a> {
+  file_path: &'a Path,
+  config: &'a Configuration,
+  command: &'a CommandConfiguration,
+  /// The range to format when formatting a range with a language server.
+  range: Option<Range<usize>>,
+  token: &'a Arc<dyn CancellationToken>,
+  result_cache: &'a ResultCache,
+  lsp_servers: &'a LspServers,
+}
+
+/// Formats the provided bytes with the command, returning `None` when
+/// the format was cancelled.
+async fn run_command(ctx: &CommandContext<'_>, file_bytes: Vec<u8>) -> Result<Option<Vec<u8>>> {
+  let CommandContext {
+    file_path,
+    config,
+    command,
+    ..
+  } = ctx;
+  let args = command
+    .arg_templates
+    .render(file_path)
+    .map_err(|message| anyhow!("Cannot format: {}", message))?;
+
+  // commands that don't use stdin read the file themselves
+  let result_key = (config.memoize && command.stdin && ctx.range.is_none())
+    .then(|| compute_result_key(config, command, &args, &file_bytes));
+  if let Some(result_key) = &result_key
+    && let Some(output) = ctx
+      .result_cache
+      .get(result_key, config.memoize_dir.as_deref())
+  {
+    return Ok(Some(output));
+  }
+
+  // output other than the text is applied to the bytes provided to the command
+  let provided_bytes = (command.output != CommandOutput::Text
+    || command.empty_output == EmptyOutput::Unchanged
+    || command.mode == CommandMode::Check)
+    .then(|| file_bytes.clone());
+  let output = match &command.command_type {
+    CommandType::Exec => match &command.transport {
+      Some(transport) => match send_to_service(ctx, transport, &args, file_bytes.clone()).await? {
+        Some(SocketResponse::Formatted(output)) => Some(output),
+        // fall back to spawning the command when the service isn't running
+        Some(SocketResponse::Unavailable) => run_process(ctx, args, file_bytes).await?,
+        None => None,
+      },
+      None => run_process(ctx, args, file_bytes).await?,
+    },
+    CommandType::Lsp { .. } => {
+      let text = String::from_utf8(file_bytes).map_err(|_| {
+        anyhow!("Cannot format a file that is not valid UTF-8 with a language server.")
+      })?;
+      run_language_server(ctx, &args, text, ctx.range.clone())
         .await?
-      {
-        SetupRun::Completed => {}
-        SetupRun::Cancelled => return Ok(None),
+        .map(String::into_bytes)
+    }
+  };
+  let output = match (output, provided_bytes) {
+    // checks never change the text
+    (Some(output), Some(provided_bytes)) if command.mode == CommandMode::Check => {
+      if command.output == CommandOutput::Json {
+        log_json_diagnostics(file_path, &JsonOutput::parse(&output)?.diagnostics);
       }
+      Some(provided_bytes)
     }
+    (Some(output), provided_bytes) if command.output == CommandOutput::Text => Some(
+      handle_empty_output(command.empty_output, output, provided_bytes)?,
+    ),
+    (Some(output), Some(provided_bytes)) => Some(match command.output {
+      CommandOutput::Json => apply_json_output(file_path, &provided_bytes, &output)?,
+      kind => apply_patch_output(kind, &provided_bytes, &output)?,
+    }),
+    (output, _) => output,
+  };
+  if let Some(result_key) = &result_key
+    && let Some(output) = &output
+  {
+    ctx
+      .result_cache
+      .insert(result_key, output, config.memoize_dir.as_deref());
+  }
+  Ok(output)
+}
 
-    // format here
-    let args = maybe_substitute_variables(&file_path, &config, command);
-
-    let mut child = ChildKillOnDrop(
-      Command::new(&command.executable)
-        .current_dir(&command.cwd)
-        .stdout(Stdio::piped())
-        .stdin(if command.stdin {
-          Stdio::piped()
-        } else {
-          Stdio::null()
-        })
-        .stderr(Stdio::piped())
-        .args(args)
-        .spawn()
-        .map_err(|e| anyhow!("Cannot start formatter process: {}", e))?,
-    );
+/// Handles a command outputting nothing, which some tools do when the file is
+/// already formatted.
+fn handle_empty_output(
+  empty_output: EmptyOutput,
+  output: Vec<u8>,
+  provided_bytes: Option<Vec<u8>>,
+) -> Result<Vec<u8>> {
+  if !output.trim_ascii().is_empty() {
+    return Ok(output);
+  }
+  match empty_output {
+    EmptyOutput::Empty => Ok(output),
+    EmptyOutput::Unchanged => Ok(provided_bytes.expect("Expected the provided bytes to be kept.")),
+    EmptyOutput::Error => bail!(concat!(
+      "The command did not output any text. Set \"emptyOutput\" to \"unchanged\" ",
+      "if that means the file is already formatted."
+    )),
+  }
+}
 
-    // capturing stdout
-    let (out_tx, out_rx) = oneshot::channel();
-    let mut handles = Vec::with_capacity(2);
-    if let Some(stdout) = child.stdout.take() {
-      handles.push(dprint_core::async_runtime::spawn_blocking(|| {
-        read_stream_lines(stdout, out_tx)
-      }));
-    } else {
-      let _ = child.kill();
-      return Err(anyhow!("Formatter did not have a handle for stdout"));
-    }
+/// Applies the patch output by a command to the bytes it was provided, where
+/// empty output means the bytes are unchanged.
+fn apply_patch_output(kind: CommandOutput, file_bytes: &[u8], output: &[u8]) -> Result<Vec<u8>> {
+  let output = std::str::from_utf8(output)
+    .map_err(|_| anyhow!("Cannot apply the command's output because it is not valid UTF-8."))?;
+  if output.trim().is_empty() {
+    return Ok(file_bytes.to_vec());
+  }
+  let result = match kind {
+    CommandOutput::Text | CommandOutput::Json => unreachable!(),
+    CommandOutput::UnifiedDiff => std::str::from_utf8(file_bytes)
+      .map_err(|_| anyhow!("The file is not valid UTF-8."))
+      .and_then(|text| apply_unified_diff(text, output))
+      .map(String::into_bytes),
+    CommandOutput::XmlReplacements => apply_xml_replacements(file_bytes, output),
+  };
+  result.map_err(|err| anyhow!("Cannot apply the command's output: {:#}", err))
+}
 
-    // capturing stderr
-    let (err_tx, err_rx) = oneshot::channel();
-    if let Some(stderr) = child.stderr.take() {
-      handles.push(dprint_core::async_runtime::spawn_blocking(|| {
-        read_stream_lines(stderr, err_tx)
-      }));
+/// Gets the formatted bytes from the JSON output of a command, logging its
+/// diagnostics.
+fn apply_json_output(file_path: &Path, file_bytes: &[u8], output: &[u8]) -> Result<Vec<u8>> {
+  let output = JsonOutput::parse(output)?;
+  let bytes = match (output.changed, output.text) {
+    (Some(false), _) => file_bytes.to_vec(),
+    (_, Some(text)) => text.into_bytes(),
+    (_, None) if output.diagnostics.is_empty() => {
+      bail!("The command's JSON output did not contain the formatted text.")
     }
+    (_, None) => bail!(
+      "The command's JSON output did not contain the formatted text:\n{}",
+      render_diagnostics(file_path, &output.diagnostics)
+    ),
+  };
+  log_json_diagnostics(file_path, &output.diagnostics);
+  Ok(bytes)
+}
+
+fn log_json_diagnostics(file_path: &Path, diagnostics: &[JsonDiagnostic]) {
+  for diagnostic in diagnostics {
+    eprintln!("[dprint-plugin-exec] {}", diagnostic.render(file_path));
+  }
+}
+
+/// Sends the bytes to the command's formatting service, returning `None` when
+/// the format was cancelled.
+async fn send_to_service(
+  ctx: &CommandContext<'_>,
+  transport: &TransportConfiguration,
+  args: &[String],
+  file_bytes: Vec<u8>,
+) -> Result<Option<SocketResponse>> {
+  let metadata = SocketRequestMetadata {
+    file_path: ctx.command.cwd.join(ctx.file_path),
+    executable: ctx.command.executable.clone(),
+    args: args.to_vec(),
+    cwd: ctx.command.cwd.clone(),
+    line_width: ctx.config.line_width,
+    indent_width: ctx.config.indent_width,
+    use_tabs: ctx.config.use_tabs,
+  };
+  let socket_path = transport.unix_socket.clone();
+  let timeout = Duration::from_secs(ctx.config.timeout as u64);
+  let request = dprint_core::async_runtime::spawn_blocking(move || {
+    send_socket_request(&socket_path, &metadata, &file_bytes, timeout)
+  });
+  tokio::select! {
+    _ = ctx.token.wait_cancellation() => Ok(None),
+    _ = tokio::time::sleep(timeout) => Err(timeout_err(ctx.config)),
+    result = request => result?.map(Some).map_err(|err| {
+      anyhow!(
+        "Formatter service at '{}' failed: {:#}",
+        transport.unix_socket.display(),
+        err
+      )
+    }),
+  }
+}
+
+/// Formats the text with the command's language server, returning `None` when
+/// the format was cancelled.
+async fn run_language_server(
+  ctx: &CommandContext<'_>,
+  args: &[String],
+  text: String,
+  range: Option<Range<usize>>,
+) -> Result<Option<String>> {
+  let CommandType::Lsp { language_id } = &ctx.command.command_type else {
+    unreachable!();
+  };
+  let language_id = language_id.clone().unwrap_or_else(|| {
+    ctx
+      .file_path
+      .extension()
+      .map(|ext| ext.to_string_lossy().to_string())
+      .unwrap_or_default()
+  });
+  let document = LspDocument {
+    file_path: ctx.file_path,
+    language_id: &language_id,
+    text,
+    range,
+  };
+  ctx
+    .lsp_servers
+    .format(ctx.config, ctx.command, args, document, ctx.token)
+    .await
+}
+
+/// Runs the command's process for the provided bytes, returning `None` when
+/// the format was cancelled.
+async fn run_process(
+  ctx: &CommandContext<'_>,
+  args: Vec<String>,
+  file_bytes: Vec<u8>,
+) -> Result<Option<Vec<u8>>> {
+  let CommandContext {
+    config,
+    command,
+    token,
+    ..
+  } = ctx;
 
-    // write file text into child's stdin
-    if command.stdin {
-      let mut stdin = child
-        .stdin
-        .take()
-        .ok_or_else(|| {
-          anyhow!(
-            "Cannot open the command's stdin. Perhaps you meant to set the command's \"stdin\" configuration to false?",
-          )
-        })?;
-      let file_bytes = file_bytes.into_owned();
-      dprint_core::async_runtime::spawn_blocking(move || {
-        stdin
-          .write_all(&file_bytes)
-          .map_err(|err| anyhow!("Cannot write into the command's stdin. {}", err))
+  let mut child = ChildKillOnDrop(
+    Command::new(&command.executable)
+      .current_dir(&command.cwd)
+      .envs(&command.env)
+      .stdout(Stdio::piped())
+      .stdin(if command.stdin {
+        Stdio::piped()
+      } else {
+        Stdio::null()
       })
-      .await??;
-    }
+      .stderr(Stdio::piped())
+      .args(args)
+      .spawn()
+      .map_err(|e| anyhow!("Cannot start formatter process: {}", e))?,
+  );
 
-    let child_completed = dprint_core::async_runtime::spawn_blocking(move || match child.wait() {
-      Ok(status) => Ok(status),
-      Err(e) => Err(anyhow!(
-        "Error while waiting for formatter to complete: {}",
-        e
-      )),
-    });
+  // capturing stdout
+  let (out_tx, out_rx) = oneshot::channel();
+  let mut handles = Vec::with_capacity(2);
+  if let Some(stdout) = child.stdout.take() {
+    handles.push(dprint_core::async_runtime::spawn_blocking(|| {
+      read_stream_lines(stdout, out_tx)
+    }));
+  } else {
+    let _ = child.kill();
+    return Err(anyhow!("Formatter did not have a handle for stdout"));
+  }
 
-    let result_future = async {
-      let handles_future = dprint_core::async_runtime::future::join_all(handles);
-      let (output_result, child_rs, handle_results) =
-        tokio::join!(out_rx, child_completed, handles_future);
-      let exit_status = child_rs??;
-      let output = output_result?;
-      for handle_result in handle_results {
-        handle_result??; // surface any errors capturing
-      }
-      Ok::<_, Error>((output, exit_status))
-    };
+  // capturing stderr
+  let (err_tx, err_rx) = oneshot::channel();
+  if let Some(stderr) = child.stderr.take() {
+    handles.push(dprint_core::async_runtime::spawn_blocking(|| {
+      read_stream_lines(stderr, err_tx)
+    }));
+  }
 
-    tokio::select! {
-      _ = token.wait_cancellation() => {
-        // return back the original text when cancelled
-        return Ok(None);
-      }
-      _ = tokio::time::sleep(Duration::from_secs(config.timeout as u64)) => {
-        return Err(timeout_err(&config));
+  // write file text into child's stdin
+  if command.stdin {
+    let mut stdin = child
+      .stdin
+      .take()
+      .ok_or_else(|| {
+        anyhow!(
+          "Cannot open the command's stdin. Perhaps you meant to set the command's \"stdin\" configuration to false?",
+        )
+      })?;
+    dprint_core::async_runtime::spawn_blocking(move || {
+      stdin
+        .write_all(&file_bytes)
+        .map_err(|err| anyhow!("Cannot write into the command's stdin. {}", err))
+    })
+    .await??;
+  }
+
+  let child_completed = dprint_core::async_runtime::spawn_blocking(move || match child.wait() {
+    Ok(status) => Ok(status),
+    Err(e) => Err(anyhow!(
+      "Error while waiting for formatter to complete: {}",
+      e
+    )),
+  });
+
+  let result_future = async {
+    let handles_future = dprint_core::async_runtime::future::join_all(handles);
+    let (output_result, child_rs, handle_results) =
+      tokio::join!(out_rx, child_completed, handles_future);
+    let exit_status = child_rs??;
+    let output = output_result?;
+    for handle_result in handle_results {
+      handle_result??; // surface any errors capturing
+    }
+    Ok::<_, Error>((output, exit_status))
+  };
+
+  tokio::select! {
+    _ = token.wait_cancellation() => Ok(None),
+    _ = tokio::time::sleep(Duration::from_secs(config.timeout as u64)) => {
+      Err(timeout_err(config))
+    }
+    result = result_future => {
+      let (ok_text, exit_status) = result?;
+      // like `diff`, some tools exit with code 1 when there are differences
+      if command.output == CommandOutput::UnifiedDiff
+        && exit_status.code() == Some(1)
+        && !ok_text.is_empty()
+      {
+        return Ok(Some(ok_text));
       }
-      result = result_future => {
-        let (ok_text, exit_status) = result?;
-        file_bytes = Cow::Owned(handle_child_exit_status(ok_text, err_rx, exit_status).await?)
+      // report the diagnostics of a failed command instead of its stderr
+      if command.output == CommandOutput::Json
+        && !exit_status.success()
+        && let Ok(output) = JsonOutput::parse(&ok_text)
+        && !output.diagnostics.is_empty()
+      {
+        bail!(
+          "Child process exited with code {}:\n{}",
+          exit_status
+            .code()
+            .map(|code| code.to_string())
+            .unwrap_or_else(|| "unknown".to_string()),
+          render_diagnostics(ctx.file_path, &output.diagnostics)
+        );
       }
+      Ok(Some(handle_child_exit_status(ctx, ok_text, err_rx, exit_status).await?))
     }
   }
+}
 
-  const MIN_CHARS_TO_EMPTY: usize = 100;
-  Ok(if *file_bytes == original_file_bytes {
-    None
-  } else if trim_bytes_len(&original_file_bytes) > MIN_CHARS_TO_EMPTY
-    && trim_bytes_len(&file_bytes) == 0
+/// Formats the file with the command while keeping its ignored blocks and
+/// front matter out of the command's reach.
+async fn format_protected(
+  ctx: &CommandContext<'_>,
+  file_bytes: Vec<u8>,
+) -> Result<Option<Vec<u8>>> {
+  // commands that don't use stdin read the file themselves, so they never
+  // see the protected text
+  let protected = std::str::from_utf8(&file_bytes)
+    .ok()
+    .filter(|_| ctx.command.stdin)
+    .and_then(|text| ProtectedText::new(text, &ctx.command.protect));
+  let Some(protected) = protected else {
+    return format_text(ctx, file_bytes).await;
+  };
+  let formatted = format_text(ctx, protected.text.as_bytes().to_vec()).await?;
+  let Some(formatted) = formatted else {
+    return Ok(None);
+  };
+  let formatted =
+    String::from_utf8(formatted).map_err(|_| anyhow!("The formatted text was not valid UTF-8."))?;
+  Ok(Some(protected.restore(&formatted)?.into_bytes()))
+}
+
+async fn format_text(ctx: &CommandContext<'_>, file_bytes: Vec<u8>) -> Result<Option<Vec<u8>>> {
+  match &ctx.command.regions {
+    Some(regions) => format_regions(ctx, file_bytes, regions).await,
+    None => run_command(ctx, file_bytes).await,
+  }
+}
+
+/// Formats each of the file's embedded regions with the command and splices
+/// the formatted text back into the file.
+async fn format_regions(
+  ctx: &CommandContext<'_>,
+  file_bytes: Vec<u8>,
+  regions: &RegionsConfiguration,
+) -> Result<Option<Vec<u8>>> {
+  let text = String::from_utf8(file_bytes)
+    .map_err(|_| anyhow!("Cannot format regions of a file that is not valid UTF-8."))?;
+  let mut result = String::with_capacity(text.len());
+  let mut last_end = 0;
+  for region in find_regions(&text, &regions.start, &regions.end)? {
+    let region_text = &text[region.range.clone()];
+    if region_text.trim().is_empty() {
+      continue;
+    }
+    let (indent, region_text) = dedent(region_text);
+    let formatted = run_command(ctx, region_text.into_bytes())
+      .await
+      .and_then(|maybe_bytes| {
+        maybe_bytes
+          .map(|bytes| String::from_utf8(bytes).map_err(Error::from))
+          .transpose()
+      })
+      .map_err(|err| {
+        anyhow!(
+          "Error formatting region at lines {}-{}: {:#}",
+          region.start_line,
+          region.end_line,
+          err
+        )
+      })?;
+    let Some(formatted) = formatted else {
+      return Ok(None);
+    };
+    result.push_str(&text[last_end..region.range.start]);
+    result.push_str(&reindent(&formatted, indent));
+    last_end = region.range.end;
+  }
+  result.push_str(&text[last_end..]);
+  Ok(Some(result.into_bytes()))
+}
+
+/// Formats each of the notebook's code cells of the provided language with the
+/// command, leaving the rest of the notebook untouched.
+async fn format_notebook(
+  ctx: &CommandContext<'_>,
+  file_bytes: Vec<u8>,
+  language: &str,
+) -> Result<Option<Vec<u8>>> {
+  let text = String::from_utf8(file_bytes)
+    .map_err(|_| anyhow!("Cannot format a notebook that is not valid UTF-8."))?;
+  let notebook = Notebook::parse(&text).map_err(|err| anyhow!("Invalid notebook: {:#}", err))?;
+  if !notebook
+    .language
+    .as_ref()
+    .is_some_and(|l| l.eq_ignore_ascii_case(language))
   {
-    // prevent someone formatting all their files to empty files
-    bail!(
-      concat!(
-        "The original file text was greater than {} characters, but the formatted text was empty. ",
-        "Perhaps dprint-plugin-exec has been misconfigured?",
-      ),
-      MIN_CHARS_TO_EMPTY
-    )
-  } else {
-    Some(file_bytes.into_owned())
-  })
+    return Ok(Some(text.into_bytes()));
+  }
+  let mut replacements = Vec::new();
+  for cell in &notebook.cells {
+    // cell magics (ex. %%bash) change the language of the entire cell
+    if cell.source.trim().is_empty() || cell.source.trim_start().starts_with("%%") {
+      continue;
+    }
+    let (masked, magics) = mask_magics(&cell.source);
+    let formatted = run_command(ctx, masked.into_bytes())
+      .await
+      .and_then(|maybe_bytes| {
+        maybe_bytes
+          .map(|bytes| {
+            let text = String::from_utf8(bytes)?;
+            // keep the cell's original trailing newlines, which are usually none
+            let source_end = cell.source.trim_end_matches(['\r', '\n']).len();
+            let text = unmask_magics(text.trim_end_matches(['\r', '\n']), &magics)?;
+            Ok(text + &cell.source[source_end..])
+          })
+          .transpose()
+      })
+      .map_err(|err| anyhow!("Error formatting notebook cell {}: {:#}", cell.index, err))?;
+    let Some(formatted) = formatted else {
+      return Ok(None);
+    };
+    if formatted != cell.source {
+      replacements.push((cell, formatted));
+    }
+  }
+  Ok(Some(replace_sources(&text, &replacements).into_bytes()))
+}
+
+/// Number of bytes at the start of a file searched for generated markers.
+const GENERATED_MARKER_SEARCH_LEN: usize = 1024;
+
+fn get_skip_reason(skip: &SkipConfiguration, file_bytes: &[u8]) -> Option<String> {
+  if let Some(max_file_size) = skip.max_file_size
+    && file_bytes.len() as u64 > max_file_size
+  {
+    return Some(format!(
+      "it is larger than the maximum file size of {} bytes",
+      max_file_size
+    ));
+  }
+  if skip.binary && file_bytes.contains(&0) {
+    return Some("it is binary".to_string());
+  }
+  let header = &file_bytes[..file_bytes.len().min(GENERATED_MARKER_SEARCH_LEN)];
+  skip
+    .generated_markers
+    .iter()
+    .find(|marker| {
+      header
+        .windows(marker.len())
+        .any(|window| window == marker.as_bytes())
+    })
+    .map(|marker| format!("it contains the generated marker '{}'", marker))
 }
 
 fn select_commands<'a>(
@@ -303,15 +937,22 @@ fn select_commands<'a>(
     bail!("Cannot format because the configuration was not valid.");
   }
 
-  let mut binaries = Vec::new();
+  let mut binaries: Vec<&CommandConfiguration> = Vec::new();
 
   for command in &config.commands {
-    if let Some(associations) = &command.associations {
-      if associations.is_match(file_path) {
-        binaries.push(command);
-      }
-    } else if binaries.is_empty() && command.matches_exts_or_filenames(file_path) {
-      binaries.push(command);
+    if !command.matches(file_path) {
+      continue;
+    }
+    // unless a chain is requested, commands matched by their extensions or
+    // file names only format files that no earlier command matched
+    if !command.overrides_chain()
+      && command.associations.is_none()
+      && binaries.last().is_some_and(|last| !last.overrides_chain())
+    {
+      continue;
+    }
+    binaries.push(command);
+    if command.stops_chain() {
       break;
     }
   }
@@ -320,21 +961,42 @@ fn select_commands<'a>(
 }
 
 async fn handle_child_exit_status(
+  ctx: &CommandContext<'_>,
   ok_text: Vec<u8>,
   err_rx: Receiver<Vec<u8>>,
   exit_status: ExitStatus,
 ) -> Result<Vec<u8>, Error> {
+  let err_text = err_rx
+    .await
+    .expect("Could not propagate error message from child process");
+  let err_text = String::from_utf8_lossy(&err_text);
   if exit_status.success() {
+    if err_text.trim().is_empty() {
+      return Ok(ok_text);
+    }
+    if ctx.command.fail_on_stderr {
+      bail!("Child process wrote to stderr: {}", err_text);
+    }
+    // pass on warnings of successful commands to the plugin's log
+    for line in err_text.trim_end().lines() {
+      eprintln!(
+        "[dprint-plugin-exec] [{}] {}: {}",
+        ctx.command.executable,
+        ctx.file_path.display(),
+        line
+      );
+    }
     return Ok(ok_text);
   }
+  // checks usually report their findings on stdout
+  let message = match ctx.command.mode {
+    CommandMode::Format => err_text.into_owned(),
+    CommandMode::Check => format!("{}{}", String::from_utf8_lossy(&ok_text), err_text),
+  };
   Err(anyhow!(
     "Child process exited with code {}: {}",
     exit_status.code().unwrap(),
-    String::from_utf8_lossy(
-      &err_rx
-        .await
-        .expect("Could not propagate error message from child process")
-    )
+    message
   ))
 }
 
@@ -350,7 +1012,16 @@ fn timeout_err(config: &Configuration) -> Error {
 /// formatted in parallel (see https://github.com/dprint/dprint/issues/1023).
 #[derive(Default, Clone)]
 pub struct SetupState {
-  cells: Rc<RefCell<HashMap<String, Rc<OnceCell<()>>>>>,
+  entries: Rc<RefCell<HashMap<String, Rc<SetupEntry>>>>,
+  /// The `afterAll` commands of the configurations that were used.
+  after_all: AfterAllHooks,
+}
+
+#[derive(Default)]
+struct SetupEntry {
+  cell: OnceCell<()>,
+  /// Number of times the setup failed and the message of the last failure.
+  failures: RefCell<(u32, String)>,
 }
 
 enum SetupRun {
@@ -358,7 +1029,7 @@ enum SetupRun {
   Cancelled,
 }
 
-enum SetupInitError {
+pub(crate) enum SetupInitError {
   Cancelled,
   Failed(Error),
 }
@@ -366,29 +1037,51 @@ enum SetupInitError {
 impl SetupState {
   async fn run_once(
     &self,
-    cwd: &Path,
     setup_command: &SetupCommand,
+    cache_key: &str,
     token: &Arc<dyn CancellationToken>,
   ) -> Result<SetupRun> {
-    // the cwd is part of the key because the same command run in different
-    // directories may produce different results
+    // the cwd and env are part of the key because the same command run in
+    // different directories or environments may produce different results and
+    // the cache key is so the setup runs again once its inputs change
     let key = format!(
-      "{}\0{} {}",
-      cwd.display(),
+      "{}\0{:?}\0{}\0{} {}",
+      setup_command.cwd.display(),
+      setup_command.env,
+      cache_key,
       setup_command.executable,
       setup_command.args.join(" ")
     );
-    let cell = {
-      let mut cells = self.cells.borrow_mut();
-      cells.entry(key).or_default().clone()
+    let entry = {
+      let mut entries = self.entries.borrow_mut();
+      entries.entry(key.clone()).or_default().clone()
     };
     // get_or_try_init ensures only one caller runs the setup at a time and that
-    // the others wait for it to finish; a failure is not cached so it can be
-    // retried by the next file rather than poisoning all formatting
-    match cell
-      .get_or_try_init(|| run_setup_command(cwd, setup_command, token))
-      .await
-    {
+    // the others wait for it to finish; a failure is not cached by the cell so
+    // whether the next file retries it is decided by the retry policy
+    let init = || async {
+      {
+        let (count, message) = &*entry.failures.borrow();
+        if *count > 0 && !setup_command.retry.allows_retry(*count) {
+          return Err(SetupInitError::Failed(anyhow!(
+            "Setup command '{}' previously failed: {}",
+            setup_command.executable,
+            message
+          )));
+        }
+      }
+      let result = match &setup_command.stamp_dir {
+        Some(stamp_dir) => run_stamped_setup_command(setup_command, stamp_dir, &key, token).await,
+        None => run_setup_command(setup_command, token).await,
+      };
+      if let Err(SetupInitError::Failed(err)) = &result {
+        let mut failures = entry.failures.borrow_mut();
+        failures.0 += 1;
+        failures.1 = err.to_string();
+      }
+      result
+    };
+    match entry.cell.get_or_try_init(init).await {
       Ok(()) => Ok(SetupRun::Completed),
       Err(SetupInitError::Cancelled) => Ok(SetupRun::Cancelled),
       Err(SetupInitError::Failed(err)) => Err(err),
@@ -396,17 +1089,62 @@ impl SetupState {
   }
 }
 
-async fn run_setup_command(
-  cwd: &Path,
+/// Runs the setup command unless a stamp file shows it already succeeded for
+/// the key, which may have been in another process. An exclusive lock on a
+/// file next to the stamp prevents processes from running it concurrently.
+async fn run_stamped_setup_command(
+  setup_command: &SetupCommand,
+  stamp_dir: &Path,
+  key: &str,
+  token: &Arc<dyn CancellationToken>,
+) -> Result<(), SetupInitError> {
+  let name = format!("{:x}", Sha256::digest(key.as_bytes()));
+  let stamp_path = stamp_dir.join(format!("{}.stamp", name));
+  if stamp_path.exists() {
+    return Ok(());
+  }
+
+  let lock_path = stamp_dir.join(format!("{}.lock", name));
+  let lock_file = dprint_core::async_runtime::spawn_blocking(move || {
+    std::fs::create_dir_all(lock_path.parent().unwrap())?;
+    let file = std::fs::File::create(&lock_path)?;
+    file.lock()?;
+    Ok::<_, std::io::Error>(file)
+  });
+  let _lock_file = tokio::select! {
+    _ = token.wait_cancellation() => return Err(SetupInitError::Cancelled),
+    result = lock_file => result
+      .map_err(|err| SetupInitError::Failed(err.into()))?
+      .map_err(|err| {
+        SetupInitError::Failed(anyhow!("Cannot lock the setup stamp directory: {}", err))
+      })?,
+  };
+  // another process may have completed the setup while waiting for the lock
+  if stamp_path.exists() {
+    return Ok(());
+  }
+  run_setup_command(setup_command, token).await?;
+  std::fs::write(&stamp_path, key)
+    .map_err(|err| SetupInitError::Failed(anyhow!("Cannot write the setup stamp file: {}", err)))
+  // the lock is released once the file is dropped
+}
+
+pub(crate) async fn run_setup_command(
   setup_command: &SetupCommand,
   token: &Arc<dyn CancellationToken>,
 ) -> Result<(), SetupInitError> {
   let mut child = ChildKillOnDrop(
     Command::new(&setup_command.executable)
-      .current_dir(cwd)
+      .current_dir(&setup_command.cwd)
+      .envs(&setup_command.env)
       .stdin(Stdio::null())
-      // a plugin must not write to stdout (it's the protocol channel)
-      .stdout(Stdio::null())
+      // a plugin must not write to stdout (it's the protocol channel), so
+      // the output is only read in order to log it to stderr
+      .stdout(if setup_command.log {
+        Stdio::piped()
+      } else {
+        Stdio::null()
+      })
       .stderr(Stdio::piped())
       .args(&setup_command.args)
       .spawn()
@@ -415,19 +1153,32 @@ async fn run_setup_command(
 
   // capture stderr to surface it if the command fails
   let (err_tx, err_rx) = oneshot::channel();
-  let mut handles = Vec::with_capacity(1);
-  if let Some(stderr) = child.stderr.take() {
-    handles.push(dprint_core::async_runtime::spawn_blocking(|| {
-      read_stream_lines(stderr, err_tx)
+  let mut handles = Vec::with_capacity(2);
+  let log_prefix = format!("[dprint-plugin-exec] [{}] ", setup_command.executable);
+  if let Some(stdout) = child.stdout.take() {
+    let log_prefix = log_prefix.clone();
+    handles.push(dprint_core::async_runtime::spawn_blocking(move || {
+      log_stream_lines(stdout, &log_prefix, None)
     }));
   }
+  if let Some(stderr) = child.stderr.take() {
+    if setup_command.log {
+      handles.push(dprint_core::async_runtime::spawn_blocking(move || {
+        log_stream_lines(stderr, &log_prefix, Some(err_tx))
+      }));
+    } else {
+      handles.push(dprint_core::async_runtime::spawn_blocking(|| {
+        read_stream_lines(stderr, err_tx)
+      }));
+    }
+  }
+
+  let child_completed = dprint_core::async_runtime::spawn_blocking(move || {
+    child
+      .wait()
+      .map_err(|e| anyhow!("Error while waiting for setup command to complete: {}", e))
+  });
 
-  let child_completed = dprint_core::async_runtime::spawn_blocking(move || {
-    child
-      .wait()
-      .map_err(|e| anyhow!("Error while waiting for setup command to complete: {}", e))
-  });
-
   let result_future = async {
     let handles_future = dprint_core::async_runtime::future::join_all(handles);
     let (child_rs, handle_results) = tokio::join!(child_completed, handles_future);
@@ -438,8 +1189,15 @@ async fn run_setup_command(
     Ok::<_, Error>(exit_status)
   };
 
+  let timeout_future = tokio::time::sleep(Duration::from_secs(setup_command.timeout as u64));
+
   tokio::select! {
     _ = token.wait_cancellation() => Err(SetupInitError::Cancelled),
+    _ = timeout_future => Err(SetupInitError::Failed(anyhow!(
+      "Setup command '{}' did not complete within {} seconds.",
+      setup_command.executable,
+      setup_command.timeout,
+    ))),
     result = result_future => match result {
       Ok(exit_status) if exit_status.success() => Ok(()),
       Ok(exit_status) => Err(SetupInitError::Failed(anyhow!(
@@ -456,6 +1214,25 @@ async fn run_setup_command(
   }
 }
 
+/// Logs each line of the stream to stderr with the prefix, sending all the
+/// bytes read to the sender when provided.
+fn log_stream_lines<R>(readable: R, prefix: &str, sender: Option<Sender<Vec<u8>>>) -> Result<()>
+where
+  R: std::io::Read,
+{
+  let mut reader = std::io::BufReader::new(readable);
+  let mut bytes = Vec::new();
+  let mut line = Vec::new();
+  while reader.read_until(b'\n', &mut line)? > 0 {
+    eprintln!("{}{}", prefix, String::from_utf8_lossy(&line).trim_end());
+    bytes.append(&mut line);
+  }
+  if let Some(sender) = sender {
+    let _ignore = sender.send(bytes); // ignore error as that means the other end is closed
+  }
+  Ok(())
+}
+
 fn read_stream_lines<R>(mut readable: R, sender: Sender<Vec<u8>>) -> Result<(), Error>
 where
   R: std::io::Read + Unpin,
@@ -466,53 +1243,33 @@ where
   Ok(())
 }
 
-fn maybe_substitute_variables(
-  file_path: &Path,
-  config: &Configuration,
-  command: &CommandConfiguration,
-) -> Vec<String> {
-  let mut handlebars = Handlebars::new();
-  handlebars.set_strict_mode(true);
-
-  #[derive(Clone, Serialize, Deserialize)]
-  struct TemplateVariables {
-    file_path: String,
-    line_width: u32,
-    use_tabs: bool,
-    indent_width: u8,
-    cwd: String,
-    timeout: u32,
-  }
-
-  let vars = TemplateVariables {
-    file_path: file_path.to_string_lossy().to_string(),
-    line_width: config.line_width,
-    use_tabs: config.use_tabs,
-    indent_width: config.indent_width,
-    cwd: command.cwd.to_string_lossy().to_string(),
-    timeout: config.timeout,
-  };
-
-  let mut c_args = vec![];
-  for arg in &command.args {
-    let formatted = handlebars
-      .render_template(arg, &vars)
-      .unwrap_or_else(|err| panic!("Cannot format: {}\n\n{}", arg, err));
-    c_args.push(formatted);
-  }
-  c_args
-}
-
 #[cfg(test)]
 mod test {
   use std::path::PathBuf;
   use std::sync::Arc;
 
+  use dprint_core::plugins::AsyncPluginHandler;
+  use dprint_core::plugins::FormatConfigId;
+  use dprint_core::plugins::FormatRequest;
   use dprint_core::plugins::NullCancellationToken;
 
+  use super::ExecHandler;
+  use super::GENERATED_MARKER_SEARCH_LEN;
   use super::SetupState;
+  use super::format_bytes_with_caches;
+  use super::get_skip_reason;
+  use super::select_commands;
   use crate::configuration::Configuration;
+  use crate::configuration::SkipConfiguration;
   use crate::format_bytes;
+  use crate::lsp::LspServers;
+  use crate::memoize::ResultCache;
+
+  // forward slashes work cross-platform for these tools and avoid splitty
+  // treating backslashes in Windows paths as escapes
+  fn to_arg(path: &std::path::Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+  }
 
   #[tokio::test]
   async fn should_error_output_empty_file() {
@@ -545,13 +1302,202 @@ mod test {
   }
 
   #[tokio::test]
-  async fn runs_setup_command_once_across_formats() {
-    // forward slashes work cross-platform for these tools and avoid splitty
-    // treating backslashes in Windows paths as escapes
-    fn to_arg(path: &std::path::Path) -> String {
-      path.to_string_lossy().replace('\\', "/")
+  async fn notebook_error_reports_cell_index() {
+    let unresolved_config = serde_json::json!({
+      "commands": [{
+        "command": "deno eval \"Deno.exit(1)\"",
+        "exts": ["ipynb"],
+        "input": "notebook"
+      }]
+    });
+    let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+    let config = Configuration::resolve(unresolved_config, &Default::default()).config;
+    let notebook = serde_json::json!({
+      "cells": [
+        { "cell_type": "markdown", "metadata": {}, "source": ["# Title"] },
+        { "cell_type": "code", "metadata": {}, "outputs": [], "source": ["x = 1"] }
+      ],
+      "metadata": { "kernelspec": { "language": "python" } }
+    });
+    let result = format_bytes(
+      PathBuf::from("notebook.ipynb"),
+      notebook.to_string().into_bytes(),
+      Arc::new(config),
+      Arc::new(NullCancellationToken),
+      &SetupState::default(),
+    )
+    .await;
+    let err_text = result.err().unwrap().to_string();
+    assert!(
+      err_text.starts_with("Error formatting notebook cell 1: Child process exited with code 1"),
+      "{}",
+      err_text
+    );
+  }
+
+  #[tokio::test]
+  async fn skips_without_spawning() {
+    let unresolved_config = serde_json::json!({
+      "skipBinary": true,
+      "maxFileSize": 10,
+      "commands": [{
+        "command": "this-command-does-not-exist",
+        "exts": ["txt"],
+        "skipGeneratedMarkers": ["@generated", "DO NOT EDIT"]
+      }]
+    });
+    let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+    let config = Arc::new(Configuration::resolve(unresolved_config, &Default::default()).config);
+    for file_text in ["a\0b", "12345678901", "// @generated", "// DO NOT EDIT"] {
+      let result = format_bytes(
+        PathBuf::from("file.txt"),
+        file_text.as_bytes().to_vec(),
+        config.clone(),
+        Arc::new(NullCancellationToken),
+        &SetupState::default(),
+      )
+      .await;
+      assert!(matches!(result, Ok(None)), "{:?}", result.err());
     }
 
+    // otherwise it attempts to run the command
+    let result = format_bytes(
+      PathBuf::from("file.txt"),
+      b"text".to_vec(),
+      config,
+      Arc::new(NullCancellationToken),
+      &SetupState::default(),
+    )
+    .await;
+    assert!(
+      result
+        .err()
+        .unwrap()
+        .to_string()
+        .starts_with("Cannot start formatter process")
+    );
+  }
+
+  #[test]
+  fn skip_reason() {
+    let skip = SkipConfiguration {
+      generated_markers: vec!["@generated".to_string()],
+      binary: true,
+      max_file_size: Some(2000),
+    };
+    assert_eq!(get_skip_reason(&skip, b"text"), None);
+    assert_eq!(get_skip_reason(&skip, b"\0").unwrap(), "it is binary");
+    assert_eq!(
+      get_skip_reason(&skip, &[b'a'; 2001]).unwrap(),
+      "it is larger than the maximum file size of 2000 bytes"
+    );
+    assert_eq!(
+      get_skip_reason(&skip, b"// @generated\n").unwrap(),
+      "it contains the generated marker '@generated'"
+    );
+    // only the start of the file is searched for generated markers
+    let mut text = vec![b'a'; GENERATED_MARKER_SEARCH_LEN];
+    text.extend(b"@generated");
+    assert_eq!(get_skip_reason(&skip, &text), None);
+  }
+
+  #[test]
+  fn substitutes_args_to_zero_or_many_entries() {
+    let unresolved_config = serde_json::from_value(serde_json::json!({
+      "useTabs": false,
+      "vars": { "flags": "--a --b" },
+      "commands": [{
+        "command": "formatter {{#if use_tabs}}--use-tabs{{/if}} {{split vars.flags}} {{file_name}}",
+        "exts": ["txt"]
+      }]
+    }))
+    .unwrap();
+    let config = Configuration::resolve(unresolved_config, &Default::default()).config;
+    assert_eq!(
+      config.commands[0]
+        .arg_templates
+        .render(&PathBuf::from("dir/file.txt"))
+        .unwrap(),
+      vec!["--a", "--b", "file.txt"]
+    );
+  }
+
+  #[tokio::test]
+  async fn memoizes_results_until_cache_key_changes() {
+    let marker = std::env::temp_dir().join(format!(
+      "dprint-exec-memoize-marker-{}.txt",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_file(&marker);
+    let script = std::env::current_dir()
+      .unwrap()
+      .join("tests/resources/append-marker.js");
+    let result_cache = ResultCache::default();
+
+    for cache_key in ["1", "1", "2"] {
+      let unresolved_config = serde_json::json!({
+        "cacheKey": cache_key,
+        "memoize": true,
+        "commands": [{
+          "command": format!("deno run -A {} {}", to_arg(&script), to_arg(&marker)),
+          "exts": ["txt"]
+        }]
+      });
+      let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+      let config = Arc::new(Configuration::resolve(unresolved_config, &Default::default()).config);
+      let result = format_bytes_with_caches(
+        PathBuf::from("file.txt"),
+        b"text".to_vec(),
+        config,
+        Arc::new(NullCancellationToken),
+        &SetupState::default(),
+        &result_cache,
+        &LspServers::default(),
+      )
+      .await;
+      assert!(result.is_ok(), "{:?}", result.err());
+    }
+
+    // the command only ran for the first format and after the cache key changed
+    let marker_contents = std::fs::read_to_string(&marker).unwrap();
+    let _ = std::fs::remove_file(&marker);
+    assert_eq!(marker_contents, "xx");
+  }
+
+  #[tokio::test]
+  async fn memoizes_results_of_checks_separately() {
+    let script = std::env::current_dir()
+      .unwrap()
+      .join("tests/resources/uppercase.js");
+    let command = format!("deno run -A {}", to_arg(&script));
+    let unresolved_config = serde_json::json!({
+      "memoize": true,
+      "commands": [{
+        "command": command,
+        "mode": "check",
+        "chain": "continue",
+        "exts": ["txt"]
+      }, {
+        "command": command,
+        "exts": ["txt"]
+      }]
+    });
+    let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+    let config = Configuration::resolve(unresolved_config, &Default::default()).config;
+    let result = format_bytes(
+      PathBuf::from("file.txt"),
+      b"hello\n".to_vec(),
+      Arc::new(config),
+      Arc::new(NullCancellationToken),
+      &SetupState::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.unwrap(), b"HELLO\n");
+  }
+
+  #[tokio::test]
+  async fn runs_setup_command_once_across_formats() {
     let marker = std::env::temp_dir().join(format!(
       "dprint-exec-setup-marker-{}.txt",
       std::process::id()
@@ -590,4 +1536,653 @@ mod test {
     let _ = std::fs::remove_file(&marker);
     assert_eq!(marker_contents, "x");
   }
+
+  #[tokio::test]
+  async fn setup_retry_policy() {
+    let script = std::env::current_dir()
+      .unwrap()
+      .join("tests/resources/append-marker.js");
+    for (setup_retry, expected_runs) in [("never", 1), ("times:1", 2), ("perFile", 3)] {
+      let marker = std::env::temp_dir().join(format!(
+        "dprint-exec-setup-retry-marker-{}-{}.txt",
+        std::process::id(),
+        setup_retry.replace(':', "-"),
+      ));
+      let _ = std::fs::remove_file(&marker);
+      let unresolved_config = serde_json::json!({
+        "commands": [{
+          "command": "deno run -A ./tests/fold.ts -w 30",
+          "setupCommand": format!("deno run -A {} {} 1", to_arg(&script), to_arg(&marker)),
+          "setupRetry": setup_retry,
+          "exts": ["txt"]
+        }]
+      });
+      let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+      let config = Arc::new(Configuration::resolve(unresolved_config, &Default::default()).config);
+      let setup_state = SetupState::default();
+
+      for file_name in ["a.txt", "b.txt", "c.txt"] {
+        let result = format_bytes(
+          PathBuf::from(file_name),
+          b"hello world".to_vec(),
+          config.clone(),
+          Arc::new(NullCancellationToken),
+          &setup_state,
+        )
+        .await;
+        assert!(
+          result
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Setup command 'deno'")
+        );
+      }
+
+      let marker_contents = std::fs::read_to_string(&marker).unwrap();
+      let _ = std::fs::remove_file(&marker);
+      assert_eq!(marker_contents.len(), expected_runs, "{}", setup_retry);
+    }
+  }
+
+  #[tokio::test]
+  async fn before_all_and_after_all_hooks() {
+    let script = std::env::current_dir()
+      .unwrap()
+      .join("tests/resources/append-marker.js");
+    let before_marker = std::env::temp_dir().join(format!(
+      "dprint-exec-before-all-marker-{}.txt",
+      std::process::id()
+    ));
+    let after_marker = std::env::temp_dir().join(format!(
+      "dprint-exec-after-all-marker-{}.txt",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_file(&before_marker);
+    let _ = std::fs::remove_file(&after_marker);
+    let unresolved_config = serde_json::json!({
+      "beforeAll": format!("deno run -A {} {}", to_arg(&script), to_arg(&before_marker)),
+      "afterAll": format!("deno run -A {} {}", to_arg(&script), to_arg(&after_marker)),
+      "maxFileSize": 20,
+      "commands": [{
+        "command": "deno run -A ./tests/fold.ts -w 30",
+        "exts": ["txt"]
+      }]
+    });
+    let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+    let config = Arc::new(Configuration::resolve(unresolved_config, &Default::default()).config);
+    let setup_state = SetupState::default();
+
+    // files every command skips don't run the hooks
+    format_bytes(
+      PathBuf::from("skipped.txt"),
+      b"hello world, this text is too long".to_vec(),
+      config.clone(),
+      Arc::new(NullCancellationToken),
+      &setup_state,
+    )
+    .await
+    .unwrap();
+    assert!(!before_marker.exists());
+    setup_state.after_all.run().await;
+    assert!(!after_marker.exists());
+
+    for file_name in ["a.txt", "b.txt"] {
+      format_bytes(
+        PathBuf::from(file_name),
+        b"hello world".to_vec(),
+        config.clone(),
+        Arc::new(NullCancellationToken),
+        &setup_state,
+      )
+      .await
+      .unwrap();
+    }
+    assert_eq!(std::fs::read_to_string(&before_marker).unwrap(), "x");
+    assert!(!after_marker.exists());
+
+    // only runs once, even when run again
+    setup_state.after_all.run().await;
+    setup_state.after_all.run().await;
+    assert_eq!(std::fs::read_to_string(&after_marker).unwrap(), "x");
+    let _ = std::fs::remove_file(&before_marker);
+    let _ = std::fs::remove_file(&after_marker);
+  }
+
+  #[tokio::test]
+  async fn formats_with_language_server() {
+    let script = std::env::current_dir()
+      .unwrap()
+      .join("tests/resources/lsp-stub.js");
+    let marker =
+      std::env::temp_dir().join(format!("dprint-exec-lsp-marker-{}.txt", std::process::id()));
+    let _ = std::fs::remove_file(&marker);
+    let unresolved_config = serde_json::json!({
+      "commands": [{
+        "command": format!("deno run -A {} {}", to_arg(&script), to_arg(&marker)),
+        "type": "lsp",
+        "exts": ["txt"]
+      }]
+    });
+    let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+    let config = Arc::new(Configuration::resolve(unresolved_config, &Default::default()).config);
+    let handler = ExecHandler::default();
+    let format = |file_name: &str, text: &str, range: Option<std::ops::Range<usize>>| {
+      handler.format(
+        FormatRequest {
+          file_path: PathBuf::from(file_name),
+          file_bytes: text.as_bytes().to_vec(),
+          config_id: FormatConfigId::from_raw(1),
+          config: config.clone(),
+          range,
+          token: Arc::new(NullCancellationToken),
+        },
+        |_| unreachable!(),
+      )
+    };
+
+    let result = format("a.txt", "a😀 foo\nfoo", None).await.unwrap();
+    assert_eq!(result.unwrap(), "a😀 bar\nbar".as_bytes());
+    let result = format("b.txt", "foo\nfoo\n", Some(4..8)).await.unwrap();
+    assert_eq!(result.unwrap(), b"foo\nbar\n");
+    let err = format("c.txt", "fail", None).await.unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Language server 'deno' failed to format: cannot format"
+    );
+    // the server was kept running
+    assert_eq!(std::fs::read_to_string(&marker).unwrap(), "x");
+
+    let err = format("d.txt", "crash", None).await.unwrap_err();
+    assert!(
+      err
+        .to_string()
+        .starts_with("Language server 'deno' exited unexpectedly")
+    );
+    // a new server is started after it exits
+    let result = format("e.txt", "foo", None).await.unwrap();
+    assert_eq!(result.unwrap(), b"bar");
+    assert_eq!(std::fs::read_to_string(&marker).unwrap(), "xx");
+    // the server is told to exit once it's no longer used
+    drop(handler);
+    assert_eq!(std::fs::read_to_string(&marker).unwrap(), "xxe");
+    let _ = std::fs::remove_file(&marker);
+  }
+
+  #[tokio::test]
+  async fn formats_range_with_hooks_and_skips() {
+    let cwd = std::env::current_dir().unwrap();
+    let lsp_stub = cwd.join("tests/resources/lsp-stub.js");
+    let append_marker = cwd.join("tests/resources/append-marker.js");
+    let marker = |name: &str| {
+      let marker = std::env::temp_dir().join(format!(
+        "dprint-exec-range-{}-marker-{}.txt",
+        name,
+        std::process::id()
+      ));
+      let _ = std::fs::remove_file(&marker);
+      marker
+    };
+    let (before_marker, after_marker) = (marker("before-all"), marker("after-all"));
+    let unresolved_config = serde_json::json!({
+      "beforeAll": format!("deno run -A {} {}", to_arg(&append_marker), to_arg(&before_marker)),
+      "afterAll": format!("deno run -A {} {}", to_arg(&append_marker), to_arg(&after_marker)),
+      "commands": [{
+        "command": format!("deno run -A {}", to_arg(&lsp_stub)),
+        "type": "lsp",
+        "maxFileSize": 10,
+        "exts": ["txt"]
+      }]
+    });
+    let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+    let config = Arc::new(Configuration::resolve(unresolved_config, &Default::default()).config);
+    let handler = ExecHandler::default();
+    let format = |text: &str, range: std::ops::Range<usize>| {
+      handler.format(
+        FormatRequest {
+          file_path: PathBuf::from("file.txt"),
+          file_bytes: text.as_bytes().to_vec(),
+          config_id: FormatConfigId::from_raw(1),
+          config: config.clone(),
+          range: Some(range),
+          token: Arc::new(NullCancellationToken),
+        },
+        |_| unreachable!(),
+      )
+    };
+
+    let result = format("foo\nfoo\n", 4..8).await.unwrap();
+    assert_eq!(result.unwrap(), b"foo\nbar\n");
+    // files larger than the maximum size are skipped
+    let result = format("foo\nfoo\nfoo\nfoo\n", 4..8).await.unwrap();
+    assert_eq!(result, None);
+    assert_eq!(std::fs::read_to_string(&before_marker).unwrap(), "x");
+
+    handler.after_all_hooks().run().await;
+    assert_eq!(std::fs::read_to_string(&after_marker).unwrap(), "x");
+    let _ = std::fs::remove_file(&before_marker);
+    let _ = std::fs::remove_file(&after_marker);
+  }
+
+  #[tokio::test]
+  async fn formats_range_of_protected_text() {
+    let lsp_stub = std::env::current_dir()
+      .unwrap()
+      .join("tests/resources/lsp-stub.js");
+    let unresolved_config = serde_json::json!({
+      "commands": [{
+        "command": format!("deno run -A {}", to_arg(&lsp_stub)),
+        "type": "lsp",
+        "ignoreBlocks": true,
+        "exts": ["txt"]
+      }]
+    });
+    let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+    let config = Arc::new(Configuration::resolve(unresolved_config, &Default::default()).config);
+    let handler = ExecHandler::default();
+    let text = "foo\n// dprint-ignore-start\nfoo\n// dprint-ignore-end\nfoo\n";
+    let result = handler
+      .format(
+        FormatRequest {
+          file_path: PathBuf::from("file.txt"),
+          file_bytes: text.as_bytes().to_vec(),
+          config_id: FormatConfigId::from_raw(1),
+          config,
+          range: Some(0..4),
+          token: Arc::new(NullCancellationToken),
+        },
+        |_| unreachable!(),
+      )
+      .await
+      .unwrap();
+    // the range can't be mapped onto the protected text, so the whole file is formatted
+    assert_eq!(
+      String::from_utf8(result.unwrap()).unwrap(),
+      "bar\n// dprint-ignore-start\nfoo\n// dprint-ignore-end\nbar\n"
+    );
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn sends_to_service_or_falls_back_to_command() {
+    use std::io::Read;
+    use std::io::Write;
+
+    let dir = std::env::temp_dir().join(format!("dprint-exec-service-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket_path = dir.join("formatter.sock");
+    let unresolved_config = serde_json::json!({
+      "commands": [{
+        "command": "deno run -A ./tests/fold.ts -w 5",
+        "transport": { "unixSocket": socket_path },
+        "exts": ["txt"]
+      }]
+    });
+    let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+    let config = Arc::new(Configuration::resolve(unresolved_config, &Default::default()).config);
+    let setup_state = SetupState::default();
+    let result_cache = ResultCache::default();
+    let lsp_servers = LspServers::default();
+    let format = || {
+      format_bytes_with_caches(
+        PathBuf::from("file.txt"),
+        b"hello world".to_vec(),
+        config.clone(),
+        Arc::new(NullCancellationToken),
+        &setup_state,
+        &result_cache,
+        &lsp_servers,
+      )
+    };
+
+    // the command is spawned while the service isn't running
+    let result = format().await.unwrap();
+    assert_eq!(result.unwrap(), b"hello\nworld\n");
+
+    let listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+    let service = std::thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut request = Vec::new();
+      stream.read_to_end(&mut request).unwrap();
+      let metadata_len = u32::from_be_bytes(request[..4].try_into().unwrap()) as usize;
+      let metadata: serde_json::Value =
+        serde_json::from_slice(&request[4..4 + metadata_len]).unwrap();
+      let file_bytes = request[4 + metadata_len + 4..].to_ascii_uppercase();
+      stream.write_all(&[0]).unwrap();
+      stream
+        .write_all(&(file_bytes.len() as u32).to_be_bytes())
+        .unwrap();
+      stream.write_all(&file_bytes).unwrap();
+      metadata
+    });
+    let result = format().await.unwrap();
+    assert_eq!(result.unwrap(), b"HELLO WORLD");
+    let metadata = service.join().unwrap();
+    assert_eq!(metadata["executable"], "deno");
+    assert_eq!(
+      metadata["args"],
+      serde_json::json!(["run", "-A", "./tests/fold.ts", "-w", "5"])
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+
+  #[tokio::test]
+  async fn applies_patch_and_json_output() {
+    let script = std::env::current_dir()
+      .unwrap()
+      .join("tests/resources/print-file.js");
+    let output_file = std::env::temp_dir().join(format!(
+      "dprint-exec-patch-output-{}.txt",
+      std::process::id()
+    ));
+    let format = async |output: &str, output_text: &str, exit_code: u8| {
+      std::fs::write(&output_file, output_text).unwrap();
+      let unresolved_config = serde_json::json!({
+        "commands": [{
+          "command": format!("deno run -A {} {} {}", to_arg(&script), to_arg(&output_file), exit_code),
+          "output": output,
+          "exts": ["txt"]
+        }]
+      });
+      let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+      let config = Configuration::resolve(unresolved_config, &Default::default()).config;
+      format_bytes(
+        PathBuf::from("file.txt"),
+        b"a\nb\n".to_vec(),
+        Arc::new(config),
+        Arc::new(NullCancellationToken),
+        &SetupState::default(),
+      )
+      .await
+    };
+
+    let diff = "--- file.txt\n+++ file.txt\n@@ -2 +2 @@\n-b\n+B\n";
+    let result = format("unifiedDiff", diff, 1).await.unwrap();
+    assert_eq!(result.unwrap(), b"a\nB\n");
+    let result = format("unifiedDiff", "", 0).await.unwrap();
+    assert_eq!(result, None);
+    let err = format("unifiedDiff", "@@ -2 +2 @@\n-x\n+B\n", 0)
+      .await
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      concat!(
+        "Cannot apply the command's output: Hunk '@@ -2 +2 @@' does not apply at line 2: ",
+        "expected 'x', but found 'b'."
+      )
+    );
+    let xml = "<replacements><replacement offset='0' length='1'>A</replacement></replacements>";
+    let result = format("xmlReplacements", xml, 0).await.unwrap();
+    assert_eq!(result.unwrap(), b"A\nb\n");
+
+    let json = r#"{ "text": "a\nb\nc\n", "diagnostics": [{ "message": "warning" }] }"#;
+    let result = format("json", json, 0).await.unwrap();
+    assert_eq!(result.unwrap(), b"a\nb\nc\n");
+    let result = format("json", r#"{ "text": "ignored", "changed": false }"#, 0)
+      .await
+      .unwrap();
+    assert_eq!(result, None);
+    let json = r#"{ "diagnostics": [{ "line": 2, "col": 1, "message": "syntax error" }] }"#;
+    let err = format("json", json, 1).await.unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Child process exited with code 1:\nfile.txt:2:1: syntax error"
+    );
+    let err = format("json", json, 0).await.unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "The command's JSON output did not contain the formatted text:\nfile.txt:2:1: syntax error"
+    );
+    let _ = std::fs::remove_file(&output_file);
+  }
+
+  #[tokio::test]
+  async fn handles_empty_output_and_stderr() {
+    let format = async |empty_output: &str, fail_on_stderr: bool| {
+      // outputs nothing and warns on stderr
+      let unresolved_config = serde_json::json!({
+        "commands": [{
+          "command": "deno eval \"console.error('warning')\"",
+          "emptyOutput": empty_output,
+          "failOnStderr": fail_on_stderr,
+          "exts": ["txt"]
+        }]
+      });
+      let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+      let config = Configuration::resolve(unresolved_config, &Default::default()).config;
+      format_bytes(
+        PathBuf::from("file.txt"),
+        b"text\n".to_vec(),
+        Arc::new(config),
+        Arc::new(NullCancellationToken),
+        &SetupState::default(),
+      )
+      .await
+    };
+
+    let result = format("unchanged", false).await.unwrap();
+    assert_eq!(result, None);
+    let result = format("empty", false).await.unwrap();
+    assert_eq!(result.unwrap(), b"");
+    let err = format("error", false).await.unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      concat!(
+        "The command did not output any text. Set \"emptyOutput\" to \"unchanged\" ",
+        "if that means the file is already formatted."
+      )
+    );
+    let err = format("unchanged", true).await.unwrap_err();
+    assert_eq!(err.to_string(), "Child process wrote to stderr: warning\n");
+  }
+
+  #[tokio::test]
+  async fn runs_checks_in_chain() {
+    let cwd = std::env::current_dir().unwrap();
+    let print_file = cwd.join("tests/resources/print-file.js");
+    let check_text = cwd.join("tests/resources/check-text.js");
+    let output_file = std::env::temp_dir().join(format!(
+      "dprint-exec-check-output-{}.txt",
+      std::process::id()
+    ));
+    std::fs::write(&output_file, "good\nbad\n").unwrap();
+    let format_command = serde_json::json!({
+      "command": format!("deno run -A {} {}", to_arg(&print_file), to_arg(&output_file)),
+      "associations": "**/*.txt"
+    });
+    let check_command = serde_json::json!({
+      "command": format!("deno run -A {} bad", to_arg(&check_text)),
+      "mode": "check",
+      "associations": "**/*.txt"
+    });
+    let format = async |commands: serde_json::Value| {
+      let unresolved_config = serde_json::json!({ "commands": commands });
+      let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+      let config = Configuration::resolve(unresolved_config, &Default::default()).config;
+      format_bytes(
+        PathBuf::from("file.txt"),
+        b"good\n".to_vec(),
+        Arc::new(config),
+        Arc::new(NullCancellationToken),
+        &SetupState::default(),
+      )
+      .await
+    };
+
+    // the check's output doesn't replace the text
+    let result = format(serde_json::json!([check_command])).await.unwrap();
+    assert_eq!(result, None);
+    let result = format(serde_json::json!([check_command, format_command]))
+      .await
+      .unwrap();
+    assert_eq!(result.unwrap(), b"good\nbad\n");
+    // the check receives the text formatted by the commands before it
+    let err = format(serde_json::json!([format_command, check_command]))
+      .await
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Child process exited with code 1: line 2: found 'bad'\n"
+    );
+    let _ = std::fs::remove_file(&output_file);
+  }
+
+  #[tokio::test]
+  async fn does_not_protect_text_of_commands_without_stdin() {
+    let script = std::env::current_dir()
+      .unwrap()
+      .join("tests/resources/print-file.js");
+    let file_path = std::env::temp_dir().join(format!(
+      "dprint-exec-no-stdin-protect-{}.txt",
+      std::process::id()
+    ));
+    let text = "a\n# dprint-ignore-start\nb\n# dprint-ignore-end\nc\n";
+    std::fs::write(&file_path, text.to_uppercase()).unwrap();
+    let unresolved_config = serde_json::json!({
+      "ignoreBlocks": true,
+      "commands": [{
+        "command": format!("deno run -A {} {{{{file_path}}}}", to_arg(&script)),
+        "stdin": false,
+        "exts": ["txt"]
+      }]
+    });
+    let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+    let config = Configuration::resolve(unresolved_config, &Default::default()).config;
+    let result = format_bytes(
+      file_path.clone(),
+      text.as_bytes().to_vec(),
+      Arc::new(config),
+      Arc::new(NullCancellationToken),
+      &SetupState::default(),
+    )
+    .await
+    .unwrap();
+    // the command's output is used as-is because it read the file itself
+    assert_eq!(result.unwrap(), text.to_uppercase().into_bytes());
+    let _ = std::fs::remove_file(&file_path);
+  }
+
+  #[test]
+  fn selects_commands_by_chain() {
+    let select = |commands: serde_json::Value, file_path: &str| {
+      let unresolved_config = serde_json::json!({ "commands": commands });
+      let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+      let result = Configuration::resolve(unresolved_config, &Default::default());
+      assert_eq!(result.diagnostics, vec![]);
+      select_commands(&result.config, &PathBuf::from(file_path))
+        .unwrap()
+        .into_iter()
+        .map(|command| command.executable.clone())
+        .collect::<Vec<_>>()
+    };
+
+    // by default, extension commands don't chain
+    let commands = serde_json::json!([
+      { "command": "a", "associations": "**/*.py" },
+      { "command": "b", "exts": ["py"] },
+      { "command": "c", "exts": ["py"] },
+      { "command": "d", "associations": "**/*.py" },
+    ]);
+    assert_eq!(select(commands, "file.py"), vec!["a", "d"]);
+    let commands = serde_json::json!([
+      { "command": "b", "exts": ["py"] },
+      { "command": "d", "associations": "**/*.py" },
+    ]);
+    assert_eq!(select(commands, "file.py"), vec!["b"]);
+
+    let commands = serde_json::json!([
+      { "command": "isort", "exts": ["py"], "chain": "continue" },
+      { "command": "other", "exts": ["js"], "chain": "continue" },
+      { "command": "black", "exts": ["py"] },
+      { "command": "ruff", "exts": ["py"] },
+    ]);
+    assert_eq!(select(commands.clone(), "file.py"), vec!["isort", "black"]);
+    assert_eq!(select(commands, "file.js"), vec!["other"]);
+    let commands = serde_json::json!([
+      { "command": "a", "associations": "**/*.py", "chain": "stop" },
+      { "command": "b", "associations": "**/*.py" },
+    ]);
+    assert_eq!(select(commands, "file.py"), vec!["a"]);
+
+    // specifying the default chain is the same as omitting it
+    let commands = serde_json::json!([
+      { "command": "a", "associations": "**/*.py", "chain": "continue" },
+      { "command": "b", "exts": ["py"], "chain": "stop" },
+      { "command": "c", "associations": "**/*.py" },
+    ]);
+    assert_eq!(select(commands, "file.py"), vec!["a", "c"]);
+    let commands = serde_json::json!([
+      { "command": "a", "associations": "**/*.py" },
+      { "command": "b", "exts": ["py"], "chain": "continue" },
+    ]);
+    assert_eq!(select(commands, "file.py"), vec!["a", "b"]);
+  }
+
+  #[tokio::test]
+  async fn setup_timeout() {
+    let unresolved_config = serde_json::json!({
+      "commands": [{
+        "command": "deno run -A ./tests/fold.ts -w 30",
+        "setupCommand": "deno eval \"setTimeout(() => {}, 3_000)\"",
+        "setupTimeout": 1,
+        "exts": ["txt"]
+      }]
+    });
+    let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+    let config = Configuration::resolve(unresolved_config, &Default::default()).config;
+    let result = format_bytes(
+      PathBuf::from("a.txt"),
+      b"hello world".to_vec(),
+      Arc::new(config),
+      Arc::new(NullCancellationToken),
+      &SetupState::default(),
+    )
+    .await;
+    assert_eq!(
+      result.err().unwrap().to_string(),
+      "Setup command 'deno' did not complete within 1 seconds."
+    );
+  }
+
+  #[tokio::test]
+  async fn setup_stamp_shared_across_processes() {
+    let temp_dir =
+      std::env::temp_dir().join(format!("dprint-exec-setup-stamp-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&temp_dir);
+    let marker = temp_dir.join("marker.txt");
+    let script = std::env::current_dir()
+      .unwrap()
+      .join("tests/resources/append-marker.js");
+    std::fs::create_dir_all(&temp_dir).unwrap();
+
+    for cache_key in ["1", "1", "2"] {
+      let unresolved_config = serde_json::json!({
+        "cacheKey": cache_key,
+        "commands": [{
+          "command": "deno run -A ./tests/fold.ts -w 30",
+          "setupCommand": format!("deno run -A {} {}", to_arg(&script), to_arg(&marker)),
+          "setupStampDir": to_arg(&temp_dir.join("stamps")),
+          "exts": ["txt"]
+        }]
+      });
+      let unresolved_config = serde_json::from_value(unresolved_config).unwrap();
+      let config = Configuration::resolve(unresolved_config, &Default::default()).config;
+      // a new setup state is like a new dprint process
+      let result = format_bytes(
+        PathBuf::from("a.txt"),
+        b"hello world".to_vec(),
+        Arc::new(config),
+        Arc::new(NullCancellationToken),
+        &SetupState::default(),
+      )
+      .await;
+      assert!(result.is_ok(), "{:?}", result.err());
+    }
+
+    // only ran again once the cache key changed
+    let marker_contents = std::fs::read_to_string(&marker).unwrap();
+    let _ = std::fs::remove_dir_all(&temp_dir);
+    assert_eq!(marker_contents, "xx");
+  }
 }
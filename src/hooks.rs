@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use dprint_core::plugins::CancellationToken;
+use dprint_core::plugins::NullCancellationToken;
+
+use crate::configuration::SetupCommand;
+use crate::handler::SetupInitError;
+use crate::handler::run_setup_command;
+
+/// The `afterAll` commands of the configurations that have formatted a file,
+/// which are run once when the process shuts down.
+#[derive(Default, Clone)]
+pub struct AfterAllHooks {
+  commands: Arc<Mutex<Vec<(String, SetupCommand)>>>,
+}
+
+impl AfterAllHooks {
+  /// Registers the command to run on shut down, ignoring it when an identical
+  /// command was already registered.
+  pub fn register(&self, setup_command: &SetupCommand) {
+    let key = format!(
+      "{}\0{:?}\0{} {}",
+      setup_command.cwd.display(),
+      setup_command.env,
+      setup_command.executable,
+      setup_command.args.join(" ")
+    );
+    let mut commands = self.commands.lock().unwrap();
+    if !commands.iter().any(|(k, _)| *k == key) {
+      commands.push((key, setup_command.clone()));
+    }
+  }
+
+  /// Runs the registered commands, logging any failures. Each command only
+  /// runs once even when this is called again.
+  pub async fn run(&self) {
+    let commands = std::mem::take(&mut *self.commands.lock().unwrap());
+    let token: Arc<dyn CancellationToken> = Arc::new(NullCancellationToken);
+    for (_, setup_command) in commands {
+      if let Err(SetupInitError::Failed(err)) = run_setup_command(&setup_command, &token).await {
+        eprintln!("[dprint-plugin-exec] afterAll failed: {:#}", err);
+      }
+    }
+  }
+}
+
+/// Exits the process when the parent process is no longer running, but
+/// unlike the checker in dprint-core, runs the `afterAll` commands first.
+pub fn start_parent_process_checker_task(parent_process_id: u32, hooks: AfterAllHooks) {
+  // a dedicated thread keeps checking even when the runtime is blocked
+  std::thread::spawn(move || {
+    loop {
+      std::thread::sleep(Duration::from_secs(5));
+      if !is_process_active(parent_process_id) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+          .enable_time()
+          .build()
+          .unwrap();
+        rt.block_on(hooks.run());
+        std::process::exit(1);
+      }
+    }
+  });
+}
+
+#[cfg(unix)]
+fn is_process_active(process_id: u32) -> bool {
+  // signal of 0 checks for the existence of the process id
+  unsafe { libc::kill(process_id as i32, 0) == 0 }
+}
+
+#[cfg(windows)]
+fn is_process_active(process_id: u32) -> bool {
+  use winapi::shared::minwindef::DWORD;
+  use winapi::shared::minwindef::FALSE;
+  use winapi::shared::ntdef::NULL;
+  use winapi::shared::winerror::WAIT_TIMEOUT;
+  use winapi::um::handleapi::CloseHandle;
+  use winapi::um::processthreadsapi::OpenProcess;
+  use winapi::um::synchapi::WaitForSingleObject;
+  use winapi::um::winnt::SYNCHRONIZE;
+
+  unsafe {
+    let process = OpenProcess(SYNCHRONIZE, FALSE, process_id as DWORD);
+    if process == NULL {
+      return false;
+    }
+
+    let result = WaitForSingleObject(process, 0) == WAIT_TIMEOUT;
+    CloseHandle(process);
+    result
+  }
+}
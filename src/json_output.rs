@@ -0,0 +1,97 @@
+use std::path::Path;
+
+use anyhow::Result;
+use anyhow::anyhow;
+use serde::Deserialize;
+
+/// Output of a command with `output: "json"`.
+#[derive(Debug, Deserialize)]
+pub struct JsonOutput {
+  /// The formatted text.
+  pub text: Option<String>,
+  /// Whether the text changed, which defaults to whether there is text.
+  pub changed: Option<bool>,
+  #[serde(default)]
+  pub diagnostics: Vec<JsonDiagnostic>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JsonDiagnostic {
+  /// One-based line number.
+  pub line: Option<u32>,
+  /// One-based column number.
+  pub col: Option<u32>,
+  pub message: String,
+}
+
+impl JsonOutput {
+  pub fn parse(output: &[u8]) -> Result<Self> {
+    serde_json::from_slice(output)
+      .map_err(|err| anyhow!("Cannot parse the command's JSON output: {}", err))
+  }
+}
+
+impl JsonDiagnostic {
+  /// Renders the diagnostic like a compiler (ex. `file.txt:1:2: message`).
+  pub fn render(&self, file_path: &Path) -> String {
+    let mut text = file_path.display().to_string();
+    if let Some(line) = self.line {
+      text.push_str(&format!(":{}", line));
+      if let Some(col) = self.col {
+        text.push_str(&format!(":{}", col));
+      }
+    }
+    format!("{}: {}", text, self.message)
+  }
+}
+
+/// Renders each diagnostic on its own line.
+pub fn render_diagnostics(file_path: &Path, diagnostics: &[JsonDiagnostic]) -> String {
+  diagnostics
+    .iter()
+    .map(|diagnostic| diagnostic.render(file_path))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn parses_and_renders_diagnostics() {
+    let output = JsonOutput::parse(
+      br#"{
+        "changed": false,
+        "diagnostics": [
+          { "line": 1, "col": 2, "message": "unused variable" },
+          { "line": 3, "message": "long line" },
+          { "message": "deprecated syntax" }
+        ]
+      }"#,
+    )
+    .unwrap();
+    assert_eq!(output.text, None);
+    assert_eq!(output.changed, Some(false));
+    assert_eq!(
+      render_diagnostics(Path::new("file.txt"), &output.diagnostics),
+      concat!(
+        "file.txt:1:2: unused variable\n",
+        "file.txt:3: long line\n",
+        "file.txt: deprecated syntax",
+      )
+    );
+
+    let output = JsonOutput::parse(br#"{ "text": "formatted" }"#).unwrap();
+    assert_eq!(output.text.as_deref(), Some("formatted"));
+    assert!(output.diagnostics.is_empty());
+
+    let err = JsonOutput::parse(b"formatted").unwrap_err();
+    assert!(
+      err
+        .to_string()
+        .starts_with("Cannot parse the command's JSON output: ")
+    );
+  }
+}
@@ -1,6 +1,17 @@
 extern crate dprint_core;
 
+pub mod cache_key;
 pub mod configuration;
 pub mod handler;
+pub mod hooks;
+pub mod json_output;
+pub mod lsp;
+pub mod memoize;
+pub mod notebook;
+pub mod patch;
+pub mod protect;
+pub mod regions;
+pub mod template;
+pub mod transport;
 
 pub use handler::format_bytes;
@@ -0,0 +1,637 @@
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+use std::process::ChildStdin;
+use std::process::Command;
+use std::process::Stdio;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Error;
+use anyhow::Result;
+use anyhow::anyhow;
+use anyhow::bail;
+use dprint_core::plugins::CancellationToken;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use serde_json::json;
+use tokio::sync::OnceCell;
+use tokio::sync::oneshot;
+use tokio::sync::oneshot::error::TryRecvError;
+
+use crate::configuration::CommandConfiguration;
+use crate::configuration::Configuration;
+use crate::handler::ChildKillOnDrop;
+
+/// Number of bytes at the end of a server's stderr kept to explain why it exited.
+const MAX_STDERR_BYTES: usize = 4096;
+/// Time a server is given to shut down before it's killed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Requests waiting on a response, which is `None` once the server exited.
+type PendingRequests = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Result<Value, String>>>>>>;
+
+/// Language servers that are kept running across files so that a server is
+/// only started and initialized once per process.
+#[derive(Default, Clone)]
+pub struct LspServers {
+  servers: Rc<RefCell<HashMap<String, Rc<OnceCell<LspServer>>>>>,
+}
+
+/// A document to format with a language server.
+pub struct LspDocument<'a> {
+  pub file_path: &'a Path,
+  pub language_id: &'a str,
+  pub text: String,
+  /// Byte range of the text to format instead of the entire text.
+  pub range: Option<Range<usize>>,
+}
+
+enum LspError {
+  Cancelled,
+  /// The server responded with an error, but is still usable.
+  Response(String),
+  /// The server can't be used anymore.
+  Failed(Error),
+}
+
+impl LspServers {
+  /// Formats the document with the command's language server, starting the
+  /// server when it's not running. Returns `None` when cancelled.
+  pub async fn format(
+    &self,
+    config: &Configuration,
+    command: &CommandConfiguration,
+    args: &[String],
+    document: LspDocument<'_>,
+    token: &Arc<dyn CancellationToken>,
+  ) -> Result<Option<String>> {
+    let key = format!(
+      "{}\0{:?}\0{} {}",
+      command.cwd.display(),
+      command.env,
+      command.executable,
+      args.join(" ")
+    );
+    let cell = {
+      let mut servers = self.servers.borrow_mut();
+      servers.entry(key.clone()).or_default().clone()
+    };
+    let timeout = Duration::from_secs(config.timeout as u64);
+    let init = || LspServer::start(command, args, timeout, token);
+    let result = match cell.get_or_try_init(init).await {
+      Ok(server) => {
+        let uri = path_to_uri(&command.cwd.join(document.file_path));
+        server.format(config, &uri, document, timeout, token).await
+      }
+      Err(err) => Err(err),
+    };
+    match result {
+      Ok(text) => Ok(Some(text)),
+      Err(LspError::Cancelled) => Ok(None),
+      Err(LspError::Response(message)) => Err(anyhow!(
+        "Language server '{}' failed to format: {}",
+        command.executable,
+        message
+      )),
+      Err(LspError::Failed(err)) => {
+        // start a new server for the next file unless that already happened
+        let mut servers = self.servers.borrow_mut();
+        if servers.get(&key).is_some_and(|c| Rc::ptr_eq(c, &cell)) {
+          servers.remove(&key);
+        }
+        Err(err)
+      }
+    }
+  }
+}
+
+struct LspServer {
+  executable: String,
+  child: ChildKillOnDrop,
+  stdin: Arc<Mutex<ChildStdin>>,
+  pending: PendingRequests,
+  stderr: Arc<Mutex<Vec<u8>>>,
+  next_id: Cell<u64>,
+}
+
+impl LspServer {
+  async fn start(
+    command: &CommandConfiguration,
+    args: &[String],
+    timeout: Duration,
+    token: &Arc<dyn CancellationToken>,
+  ) -> Result<LspServer, LspError> {
+    let mut child = ChildKillOnDrop(
+      Command::new(&command.executable)
+        .current_dir(&command.cwd)
+        .envs(&command.env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .args(args)
+        .spawn()
+        .map_err(|e| LspError::Failed(anyhow!("Cannot start language server process: {}", e)))?,
+    );
+    let stdin = Arc::new(Mutex::new(child.stdin.take().unwrap()));
+    let pending: PendingRequests = Arc::new(Mutex::new(Some(HashMap::new())));
+    let stderr = Arc::new(Mutex::new(Vec::new()));
+
+    // dedicated threads are used instead of spawn_blocking because they
+    // live as long as the server and would keep the runtime from shutting down
+    let stdout = child.stdout.take().unwrap();
+    std::thread::spawn({
+      let stdin = stdin.clone();
+      let pending = pending.clone();
+      move || read_messages(stdout, &stdin, &pending)
+    });
+    let child_stderr = child.stderr.take().unwrap();
+    std::thread::spawn({
+      let stderr = stderr.clone();
+      move || read_stderr_tail(child_stderr, &stderr)
+    });
+
+    let server = LspServer {
+      executable: command.executable.clone(),
+      child,
+      stdin,
+      pending,
+      stderr,
+      next_id: Cell::new(1),
+    };
+    let root_uri = path_to_uri(&command.cwd);
+    let params = json!({
+      "processId": std::process::id(),
+      "clientInfo": {
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+      },
+      "rootPath": command.cwd,
+      "rootUri": root_uri,
+      "workspaceFolders": [{
+        "uri": root_uri,
+        "name": command.cwd.file_name().map(|n| n.to_string_lossy()).unwrap_or_default(),
+      }],
+      "capabilities": {
+        "general": { "positionEncodings": ["utf-16"] },
+        "textDocument": {
+          "formatting": { "dynamicRegistration": false },
+          "rangeFormatting": { "dynamicRegistration": false },
+        },
+      },
+    });
+    server
+      .request("initialize", params, timeout, token)
+      .await
+      .map_err(|err| match err {
+        LspError::Response(message) => LspError::Failed(anyhow!(
+          "Language server '{}' failed to initialize: {}",
+          server.executable,
+          message
+        )),
+        err => err,
+      })?;
+    server.notify("initialized", json!({})).await?;
+    Ok(server)
+  }
+
+  async fn format(
+    &self,
+    config: &Configuration,
+    uri: &str,
+    document: LspDocument<'_>,
+    timeout: Duration,
+    token: &Arc<dyn CancellationToken>,
+  ) -> Result<String, LspError> {
+    let text = document.text;
+    self
+      .notify(
+        "textDocument/didOpen",
+        json!({
+          "textDocument": {
+            "uri": uri,
+            "languageId": document.language_id,
+            "version": 1,
+            "text": text,
+          },
+        }),
+      )
+      .await?;
+    let mut params = json!({
+      "textDocument": { "uri": uri },
+      "options": {
+        "tabSize": config.indent_width,
+        "insertSpaces": !config.use_tabs,
+        "lineWidth": config.line_width,
+      },
+    });
+    let method = match &document.range {
+      Some(range) => {
+        params["range"] = json!(LspRange {
+          start: offset_to_position(&text, range.start),
+          end: offset_to_position(&text, range.end),
+        });
+        "textDocument/rangeFormatting"
+      }
+      None => "textDocument/formatting",
+    };
+    let result = self.request(method, params, timeout, token).await;
+    self
+      .notify(
+        "textDocument/didClose",
+        json!({ "textDocument": { "uri": uri } }),
+      )
+      .await?;
+    let edits = match result? {
+      Value::Null => Vec::new(),
+      value => serde_json::from_value::<Vec<TextEdit>>(value).map_err(|err| {
+        LspError::Response(format!("Invalid text edits in the response. {}", err))
+      })?,
+    };
+    apply_text_edits(&text, &edits).map_err(|err| LspError::Response(err.to_string()))
+  }
+
+  async fn request(
+    &self,
+    method: &str,
+    params: Value,
+    timeout: Duration,
+    token: &Arc<dyn CancellationToken>,
+  ) -> Result<Value, LspError> {
+    let id = self.next_id.get();
+    self.next_id.set(id + 1);
+    let (tx, rx) = oneshot::channel();
+    match self.pending.lock().unwrap().as_mut() {
+      Some(pending) => pending.insert(id, tx),
+      None => return Err(LspError::Failed(self.exited_error())),
+    };
+    self
+      .send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+      .await?;
+    tokio::select! {
+      _ = token.wait_cancellation() => {
+        self.remove_pending(id);
+        let _ignore = self
+          .notify("$/cancelRequest", json!({ "id": id }))
+          .await;
+        Err(LspError::Cancelled)
+      }
+      _ = tokio::time::sleep(timeout) => {
+        self.remove_pending(id);
+        Err(LspError::Failed(anyhow!(
+          "Language server '{}' has not responded to '{}' within {} seconds.",
+          self.executable,
+          method,
+          timeout.as_secs(),
+        )))
+      }
+      result = rx => match result {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(message)) => Err(LspError::Response(message)),
+        Err(_) => Err(LspError::Failed(self.exited_error())),
+      }
+    }
+  }
+
+  async fn notify(&self, method: &str, params: Value) -> Result<(), LspError> {
+    self
+      .send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+      .await
+  }
+
+  async fn send(&self, message: Value) -> Result<(), LspError> {
+    let stdin = self.stdin.clone();
+    dprint_core::async_runtime::spawn_blocking(move || {
+      write_message(&mut *stdin.lock().unwrap(), &message)
+    })
+    .await
+    .map_err(|err| LspError::Failed(err.into()))?
+    .map_err(|_| LspError::Failed(self.exited_error()))
+  }
+
+  fn remove_pending(&self, id: u64) {
+    if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+      pending.remove(&id);
+    }
+  }
+
+  fn exited_error(&self) -> Error {
+    anyhow!(
+      "Language server '{}' exited unexpectedly: {}",
+      self.executable,
+      String::from_utf8_lossy(&self.stderr.lock().unwrap()).trim()
+    )
+  }
+}
+
+impl Drop for LspServer {
+  /// Asks the server to shut down and exit so that it isn't left running,
+  /// which it's killed for when it takes too long.
+  fn drop(&mut self) {
+    let id = self.next_id.get();
+    let (tx, mut rx) = oneshot::channel();
+    match self.pending.lock().unwrap().as_mut() {
+      Some(pending) => pending.insert(id, tx),
+      None => return, // already exited
+    };
+    let shutdown = json!({ "jsonrpc": "2.0", "id": id, "method": "shutdown" });
+    if write_message(&mut *self.stdin.lock().unwrap(), &shutdown).is_err() {
+      return;
+    }
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while matches!(rx.try_recv(), Err(TryRecvError::Empty)) && Instant::now() < deadline {
+      std::thread::sleep(Duration::from_millis(10));
+    }
+    let exit = json!({ "jsonrpc": "2.0", "method": "exit" });
+    let _ignore = write_message(&mut *self.stdin.lock().unwrap(), &exit);
+    while matches!(self.child.try_wait(), Ok(None)) && Instant::now() < deadline {
+      std::thread::sleep(Duration::from_millis(10));
+    }
+  }
+}
+
+/// Reads the server's messages, passing responses to the requests waiting on
+/// them and replying to the server's own requests.
+fn read_messages(stdout: impl Read, stdin: &Mutex<ChildStdin>, pending: &PendingRequests) {
+  let mut reader = std::io::BufReader::new(stdout);
+  while let Ok(Some(message)) = read_message(&mut reader) {
+    let method = message.get("method").and_then(|m| m.as_str());
+    match (method, message.get("id")) {
+      (Some(method), Some(id)) => {
+        // a client that doesn't support the request may reply with null,
+        // except each requested configuration item needs its own value
+        let result = match method {
+          "workspace/configuration" => {
+            let len = message["params"]["items"].as_array().map(Vec::len);
+            json!(vec![Value::Null; len.unwrap_or(0)])
+          }
+          _ => Value::Null,
+        };
+        let reply = json!({ "jsonrpc": "2.0", "id": id, "result": result });
+        let _ignore = write_message(&mut *stdin.lock().unwrap(), &reply);
+      }
+      (None, Some(id)) => {
+        let sender = id.as_u64().and_then(|id| {
+          let mut pending = pending.lock().unwrap();
+          pending.as_mut().and_then(|pending| pending.remove(&id))
+        });
+        if let Some(sender) = sender {
+          let result = match message.get("error") {
+            Some(error) => Err(
+              error["message"]
+                .as_str()
+                .map(ToOwned::to_owned)
+                .unwrap_or_else(|| error.to_string()),
+            ),
+            None => Ok(message.get("result").cloned().unwrap_or_default()),
+          };
+          let _ignore = sender.send(result); // ignore error as that means the request was dropped
+        }
+      }
+      // notifications such as log messages aren't used
+      _ => {}
+    }
+  }
+  // dropping the senders fails the requests waiting on the server
+  pending.lock().unwrap().take();
+}
+
+fn read_message(reader: &mut impl BufRead) -> std::io::Result<Option<Value>> {
+  let mut content_length = None;
+  let mut line = String::new();
+  loop {
+    line.clear();
+    if reader.read_line(&mut line)? == 0 {
+      return Ok(None);
+    }
+    let line = line.trim_end();
+    if line.is_empty() {
+      break;
+    }
+    if let Some((name, value)) = line.split_once(':')
+      && name.eq_ignore_ascii_case("Content-Length")
+    {
+      content_length = value.trim().parse::<usize>().ok();
+    }
+  }
+  let Some(content_length) = content_length else {
+    return Err(std::io::Error::other("Missing the Content-Length header."));
+  };
+  let mut body = vec![0; content_length];
+  reader.read_exact(&mut body)?;
+  Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> std::io::Result<()> {
+  let body = serde_json::to_vec(message)?;
+  write!(writer, "Content-Length: {}\r\n\r\n", body.len())?;
+  writer.write_all(&body)?;
+  writer.flush()
+}
+
+fn read_stderr_tail(mut readable: impl Read, stderr: &Mutex<Vec<u8>>) {
+  let mut buf = [0; 1024];
+  while let Ok(len) = readable.read(&mut buf) {
+    if len == 0 {
+      break;
+    }
+    let mut stderr = stderr.lock().unwrap();
+    stderr.extend_from_slice(&buf[..len]);
+    let excess = stderr.len().saturating_sub(MAX_STDERR_BYTES);
+    stderr.drain(..excess);
+  }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct Position {
+  line: u32,
+  /// Offset in UTF-16 code units.
+  character: u32,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LspRange {
+  start: Position,
+  end: Position,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TextEdit {
+  range: LspRange,
+  new_text: String,
+}
+
+fn offset_to_position(text: &str, offset: usize) -> Position {
+  let mut offset = offset.min(text.len());
+  while !text.is_char_boundary(offset) {
+    offset -= 1;
+  }
+  let before = &text[..offset];
+  let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+  Position {
+    line: before.matches('\n').count() as u32,
+    character: before[line_start..].encode_utf16().count() as u32,
+  }
+}
+
+/// Gets the byte offset of the position, which is clamped to the end of the
+/// line or text like the specification says.
+fn position_to_offset(text: &str, position: &Position) -> usize {
+  let mut line_start = 0;
+  for _ in 0..position.line {
+    match text[line_start..].find('\n') {
+      Some(index) => line_start += index + 1,
+      None => return text.len(),
+    }
+  }
+  let line = &text[line_start..];
+  let line = &line[..line.find('\n').unwrap_or(line.len())];
+  let line = line.strip_suffix('\r').unwrap_or(line);
+  let mut character = 0;
+  for (index, c) in line.char_indices() {
+    if character >= position.character {
+      return line_start + index;
+    }
+    character += c.len_utf16() as u32;
+  }
+  line_start + line.len()
+}
+
+fn apply_text_edits(text: &str, edits: &[TextEdit]) -> Result<String> {
+  let mut edits = edits
+    .iter()
+    .map(|edit| {
+      let start = position_to_offset(text, &edit.range.start);
+      let end = position_to_offset(text, &edit.range.end);
+      (start, end, edit.new_text.as_str())
+    })
+    .collect::<Vec<_>>();
+  // inserts come before a replacement starting at the same position and a
+  // stable sort keeps the order of multiple inserts at the same position
+  edits.sort_by_key(|(start, end, _)| (*start, *end));
+  let mut result = String::with_capacity(text.len());
+  let mut last_end = 0;
+  for (start, end, new_text) in edits {
+    if start < last_end || end < start {
+      bail!("The text edits overlap.");
+    }
+    result.push_str(&text[last_end..start]);
+    result.push_str(new_text);
+    last_end = end;
+  }
+  result.push_str(&text[last_end..]);
+  Ok(result)
+}
+
+fn path_to_uri(path: &Path) -> String {
+  let path = path.to_string_lossy().replace('\\', "/");
+  // windows paths start with a drive letter
+  let mut uri = if path.starts_with('/') {
+    "file://".to_string()
+  } else {
+    "file:///".to_string()
+  };
+  for byte in path.bytes() {
+    match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+        uri.push(byte as char)
+      }
+      _ => uri.push_str(&format!("%{:02X}", byte)),
+    }
+  }
+  uri
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  fn edit(start: (u32, u32), end: (u32, u32), new_text: &str) -> TextEdit {
+    TextEdit {
+      range: LspRange {
+        start: Position {
+          line: start.0,
+          character: start.1,
+        },
+        end: Position {
+          line: end.0,
+          character: end.1,
+        },
+      },
+      new_text: new_text.to_string(),
+    }
+  }
+
+  #[test]
+  fn applies_text_edits() {
+    let text = "a😀 foo\r\nfoo\n";
+    let edits = vec![
+      edit((1, 0), (1, 3), "bar"),
+      // the emoji is two UTF-16 code units
+      edit((0, 4), (0, 7), "baz"),
+      edit((1, 3), (1, 3), "1"),
+      edit((1, 3), (1, 3), "2"),
+      // clamped to the end of the line and text
+      edit((0, 100), (0, 100), ";"),
+      edit((5, 0), (5, 0), "end"),
+    ];
+    assert_eq!(
+      apply_text_edits(text, &edits).unwrap(),
+      "a😀 baz;\r\nbar12\nend"
+    );
+
+    // an insert at the start of a replacement comes first
+    let edits = vec![edit((0, 0), (0, 1), "b"), edit((0, 0), (0, 0), "c")];
+    assert_eq!(apply_text_edits("a", &edits).unwrap(), "cb");
+
+    let edits = vec![edit((0, 0), (0, 4), ""), edit((0, 3), (0, 5), "")];
+    assert_eq!(
+      apply_text_edits(text, &edits).unwrap_err().to_string(),
+      "The text edits overlap."
+    );
+  }
+
+  #[test]
+  fn converts_offsets_to_positions() {
+    let text = "a😀b\ncd";
+    assert_eq!(
+      offset_to_position(text, 5),
+      Position {
+        line: 0,
+        character: 3
+      }
+    );
+    assert_eq!(
+      offset_to_position(text, text.len()),
+      Position {
+        line: 1,
+        character: 2
+      }
+    );
+    // within the emoji
+    assert_eq!(offset_to_position(text, 2).character, 1);
+  }
+
+  #[test]
+  fn converts_paths_to_uris() {
+    assert_eq!(
+      path_to_uri(Path::new("/home/my dir/a#b.txt")),
+      "file:///home/my%20dir/a%23b.txt"
+    );
+    assert_eq!(
+      path_to_uri(Path::new("C:\\dir\\file.txt")),
+      "file:///C:/dir/file.txt"
+    );
+  }
+}
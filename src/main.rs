@@ -1,8 +1,8 @@
 use anyhow::Result;
 use dprint_core::plugins::process::get_parent_process_id_from_cli_args;
 use dprint_core::plugins::process::handle_process_stdio_messages;
-use dprint_core::plugins::process::start_parent_process_checker_task;
 use dprint_plugin_exec::handler::ExecHandler;
+use dprint_plugin_exec::hooks::start_parent_process_checker_task;
 
 fn main() -> Result<()> {
   let rt = tokio::runtime::Builder::new_current_thread()
@@ -11,10 +11,14 @@ fn main() -> Result<()> {
     .unwrap();
 
   rt.block_on(async move {
+    let handler = ExecHandler::default();
+    let after_all_hooks = handler.after_all_hooks();
     if let Some(parent_process_id) = get_parent_process_id_from_cli_args() {
-      start_parent_process_checker_task(parent_process_id);
+      start_parent_process_checker_task(parent_process_id, after_all_hooks.clone());
     }
 
-    handle_process_stdio_messages(ExecHandler::default()).await
+    let result = handle_process_stdio_messages(handler).await;
+    after_all_hooks.run().await;
+    result
   })
 }
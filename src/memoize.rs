@@ -0,0 +1,252 @@
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::configuration::CommandConfiguration;
+use crate::configuration::Configuration;
+
+/// Maximum total size of the outputs kept in memory.
+const MAX_MEMORY_BYTES: usize = 32 * 1024 * 1024;
+/// Maximum total size of the outputs kept in a memoize directory.
+const MAX_DIR_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Remembers the output of a command for a given input so that formatting
+/// the same text with the same command again doesn't launch a process.
+#[derive(Default, Clone)]
+pub struct ResultCache {
+  entries: Rc<RefCell<LruEntries>>,
+  /// Bytes written to the directory since it was last pruned, which is
+  /// `None` until it's pruned for the first time.
+  unpruned_dir_bytes: Rc<Cell<Option<u64>>>,
+}
+
+#[derive(Default)]
+struct LruEntries {
+  /// Outputs along with when they were last used.
+  outputs: HashMap<String, (u64, Vec<u8>)>,
+  /// Keys by when they were last used.
+  order: BTreeMap<u64, String>,
+  next_use: u64,
+  total_bytes: usize,
+}
+
+impl ResultCache {
+  /// Gets the output from memory, falling back to the directory if provided.
+  pub fn get(&self, key: &str, dir: Option<&Path>) -> Option<Vec<u8>> {
+    {
+      let mut entries = self.entries.borrow_mut();
+      if let Some((_, output)) = entries.outputs.get(key).cloned() {
+        entries.touch(key);
+        return Some(output);
+      }
+    }
+    let path = dir?.join(key);
+    let output = std::fs::read(&path).ok()?;
+    // keep the file from being pruned as one of the least recently used
+    let _ignore = std::fs::File::options()
+      .write(true)
+      .open(&path)
+      .and_then(|file| file.set_modified(SystemTime::now()));
+    self.entries.borrow_mut().insert(key, output.clone());
+    Some(output)
+  }
+
+  /// Stores the output in memory and in the directory if provided. Failing
+  /// to write to the directory is ignored because it's only a cache.
+  pub fn insert(&self, key: &str, output: &[u8], dir: Option<&Path>) {
+    self.entries.borrow_mut().insert(key, output.to_vec());
+    if let Some(dir) = dir {
+      let _ignore = write_atomic(dir, key, output);
+      let unpruned_bytes = self
+        .unpruned_dir_bytes
+        .get()
+        .map(|bytes| bytes + output.len() as u64);
+      // prune when first used and then whenever enough was written to matter
+      if unpruned_bytes.is_none_or(|bytes| bytes > MAX_DIR_BYTES / 4) {
+        let _ignore = prune_dir(dir, MAX_DIR_BYTES);
+        self.unpruned_dir_bytes.set(Some(0));
+      } else {
+        self.unpruned_dir_bytes.set(unpruned_bytes);
+      }
+    }
+  }
+}
+
+impl LruEntries {
+  fn touch(&mut self, key: &str) {
+    let next_use = self.next_use;
+    if let Some((last_use, _)) = self.outputs.get_mut(key) {
+      let key = self.order.remove(last_use).unwrap();
+      *last_use = next_use;
+      self.order.insert(next_use, key);
+      self.next_use += 1;
+    }
+  }
+
+  fn insert(&mut self, key: &str, output: Vec<u8>) {
+    if output.len() > MAX_MEMORY_BYTES {
+      return;
+    }
+    self.total_bytes += output.len();
+    let entry = (self.next_use, output);
+    if let Some((last_use, previous)) = self.outputs.insert(key.to_string(), entry) {
+      self.total_bytes -= previous.len();
+      self.order.remove(&last_use);
+    }
+    self.order.insert(self.next_use, key.to_string());
+    self.next_use += 1;
+    while self.total_bytes > MAX_MEMORY_BYTES {
+      let Some((_, oldest)) = self.order.pop_first() else {
+        break;
+      };
+      if let Some((_, output)) = self.outputs.remove(&oldest) {
+        self.total_bytes -= output.len();
+      }
+    }
+  }
+}
+
+fn write_atomic(dir: &Path, key: &str, output: &[u8]) -> std::io::Result<()> {
+  std::fs::create_dir_all(dir)?;
+  // write to a temporary file first so other processes never read a partial file
+  let temp_path = dir.join(format!("{}.{}.tmp", key, std::process::id()));
+  std::fs::write(&temp_path, output)?;
+  std::fs::rename(&temp_path, dir.join(key)).inspect_err(|_| {
+    let _ignore = std::fs::remove_file(&temp_path);
+  })
+}
+
+/// Removes the least recently used files until the directory is at most the
+/// provided size.
+fn prune_dir(dir: &Path, max_bytes: u64) -> std::io::Result<()> {
+  let mut files = Vec::new();
+  let mut total_bytes = 0;
+  for entry in std::fs::read_dir(dir)? {
+    let entry = entry?;
+    let metadata = entry.metadata()?;
+    if metadata.is_file() {
+      total_bytes += metadata.len();
+      files.push((metadata.modified()?, metadata.len(), entry.path()));
+    }
+  }
+  files.sort();
+  for (_, len, path) in files {
+    if total_bytes <= max_bytes {
+      break;
+    }
+    // another process may have removed it already
+    let _ignore = std::fs::remove_file(path);
+    total_bytes -= len;
+  }
+  Ok(())
+}
+
+/// Computes the key of a command's output from everything that affects it.
+pub fn compute_result_key(
+  config: &Configuration,
+  command: &CommandConfiguration,
+  args: &[String],
+  input: &[u8],
+) -> String {
+  fn update_with_len(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+  }
+
+  let mut hasher = Sha256::new();
+  update_with_len(&mut hasher, env!("CARGO_PKG_VERSION").as_bytes());
+  update_with_len(&mut hasher, config.cache_key.as_bytes());
+  update_with_len(&mut hasher, command.cwd.to_string_lossy().as_bytes());
+  update_with_len(&mut hasher, command.executable.as_bytes());
+  hasher.update((command.env.len() as u64).to_le_bytes());
+  for (name, value) in &command.env {
+    update_with_len(&mut hasher, name.as_bytes());
+    update_with_len(&mut hasher, value.as_bytes());
+  }
+  for arg in args {
+    update_with_len(&mut hasher, arg.as_bytes());
+  }
+  // the cached result is the output after it's been processed
+  let processing = serde_json::to_vec(&(
+    &command.command_type,
+    command.mode,
+    command.output,
+    command.empty_output,
+    command.fail_on_stderr,
+  ))
+  .unwrap();
+  update_with_len(&mut hasher, &processing);
+  update_with_len(&mut hasher, input);
+  format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let mut entries = LruEntries::default();
+    let output = vec![0; MAX_MEMORY_BYTES / 3];
+    entries.insert("a", output.clone());
+    entries.insert("b", output.clone());
+    entries.insert("c", output.clone());
+    entries.touch("a");
+    entries.insert("d", output.clone());
+    let mut keys = entries.outputs.keys().cloned().collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(keys, vec!["a", "c", "d"]);
+    assert_eq!(entries.total_bytes, output.len() * 3);
+  }
+
+  #[test]
+  fn reads_and_writes_dir() {
+    let dir = std::env::temp_dir().join(format!("dprint-exec-memoize-{}", std::process::id()));
+    let cache = ResultCache::default();
+    cache.insert("key", b"output", Some(&dir));
+    // a new cache, like in another process, reads it from the directory
+    let other_cache = ResultCache::default();
+    assert_eq!(other_cache.get("key", Some(&dir)), Some(b"output".to_vec()));
+    assert_eq!(other_cache.get("key", None), Some(b"output".to_vec()));
+    assert_eq!(other_cache.get("other", Some(&dir)), None);
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn prunes_least_recently_used_files() {
+    let dir = std::env::temp_dir().join(format!("dprint-exec-prune-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let modified = SystemTime::now() - std::time::Duration::from_secs(60);
+    for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+      write_atomic(&dir, key, b"output").unwrap();
+      let file = std::fs::File::options()
+        .write(true)
+        .open(dir.join(key))
+        .unwrap();
+      file
+        .set_modified(modified + std::time::Duration::from_secs(i as u64))
+        .unwrap();
+    }
+    // reading a file marks it as recently used
+    assert_eq!(
+      ResultCache::default().get("a", Some(&dir)),
+      Some(b"output".to_vec())
+    );
+    prune_dir(&dir, 12).unwrap();
+    let mut keys = std::fs::read_dir(&dir)
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+      .collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(keys, vec!["a", "c"]);
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
@@ -0,0 +1,454 @@
+use anyhow::Result;
+use anyhow::anyhow;
+use anyhow::bail;
+use std::ops::Range;
+
+const MAGIC_PLACEHOLDER_PREFIX: &str = "__dprint_plugin_exec_magic_";
+
+/// A code cell of a Jupyter notebook.
+pub struct CodeCell {
+  /// Index of the cell in the notebook's cells.
+  pub index: usize,
+  /// The cell's source text.
+  pub source: String,
+  span: Range<usize>,
+  layout: SourceLayout,
+}
+
+/// How the cell's source was written so that a replacement can be written
+/// the same way.
+enum SourceLayout {
+  String,
+  Array {
+    open_ws: String,
+    separator: String,
+    close_ws: String,
+  },
+}
+
+/// The code cells of a notebook along with its kernel language.
+pub struct Notebook {
+  pub language: Option<String>,
+  pub cells: Vec<CodeCell>,
+}
+
+impl Notebook {
+  /// Parses the notebook without losing track of where each code cell's
+  /// source is located in the text.
+  pub fn parse(text: &str) -> Result<Notebook> {
+    let mut scanner = Scanner {
+      text,
+      bytes: text.as_bytes(),
+      pos: 0,
+    };
+    let root = scanner.parse_root()?;
+    let language = root
+      .get_path(&["metadata", "kernelspec", "language"])
+      .or_else(|| root.get_path(&["metadata", "language_info", "name"]))
+      .map(|node| node.decode_string(text))
+      .transpose()?;
+    let mut cells = Vec::new();
+    if let Some(cell_nodes) = root.get("cells").and_then(|cells| cells.as_array()) {
+      for (index, cell) in cell_nodes.iter().enumerate() {
+        let Some(cell_type) = cell.get("cell_type") else {
+          continue;
+        };
+        if cell_type.decode_string(text)? != "code" {
+          continue;
+        }
+        let Some(source) = cell.get("source") else {
+          continue;
+        };
+        let (source_text, layout) = match &source.kind {
+          NodeKind::String => (source.decode_string(text)?, SourceLayout::String),
+          NodeKind::Array(items) => {
+            if items.is_empty() {
+              continue;
+            }
+            let mut source_text = String::new();
+            for item in items {
+              source_text.push_str(&item.decode_string(text)?);
+            }
+            let first = &items[0].span;
+            let last = &items[items.len() - 1].span;
+            let open_ws = text[source.span.start + 1..first.start].to_string();
+            let separator = match items.get(1) {
+              Some(second) => text[first.end..second.span.start].to_string(),
+              None => format!(",{}", open_ws),
+            };
+            let close_ws = text[last.end..source.span.end - 1].to_string();
+            (
+              source_text,
+              SourceLayout::Array {
+                open_ws,
+                separator,
+                close_ws,
+              },
+            )
+          }
+          _ => bail!(
+            "Expected the source of cell {} to be a string or array.",
+            index
+          ),
+        };
+        cells.push(CodeCell {
+          index,
+          source: source_text,
+          span: source.span.clone(),
+          layout,
+        });
+      }
+    }
+    Ok(Notebook { language, cells })
+  }
+}
+
+/// Replaces the sources of the provided cells in the notebook text, leaving
+/// everything else as-is.
+pub fn replace_sources(text: &str, replacements: &[(&CodeCell, String)]) -> String {
+  let mut result = String::with_capacity(text.len());
+  let mut last_end = 0;
+  for (cell, source) in replacements {
+    result.push_str(&text[last_end..cell.span.start]);
+    match &cell.layout {
+      SourceLayout::String => result.push_str(&to_json_string(source)),
+      SourceLayout::Array { .. } if source.is_empty() => result.push_str("[]"),
+      SourceLayout::Array {
+        open_ws,
+        separator,
+        close_ws,
+      } => {
+        result.push('[');
+        result.push_str(open_ws);
+        for (i, line) in source.split_inclusive('\n').enumerate() {
+          if i > 0 {
+            result.push_str(separator);
+          }
+          result.push_str(&to_json_string(line));
+        }
+        result.push_str(close_ws);
+        result.push(']');
+      }
+    }
+    last_end = cell.span.end;
+  }
+  result.push_str(&text[last_end..]);
+  result
+}
+
+fn to_json_string(text: &str) -> String {
+  serde_json::to_string(text).unwrap()
+}
+
+/// Replaces the IPython magic lines (lines starting with `%` or `!`) with
+/// placeholder statements so the formatter only sees valid code. A comment
+/// wouldn't do because a magic may be the only statement of a block.
+pub fn mask_magics(source: &str) -> (String, Vec<String>) {
+  let mut magics = Vec::new();
+  let mut result = String::with_capacity(source.len());
+  for line in source.split_inclusive('\n') {
+    let content = line.trim_end_matches(['\r', '\n']);
+    let trimmed = content.trim_start();
+    if trimmed.starts_with('%') || trimmed.starts_with('!') {
+      result.push_str(&content[..content.len() - trimmed.len()]);
+      result.push_str(MAGIC_PLACEHOLDER_PREFIX);
+      result.push_str(&magics.len().to_string());
+      result.push_str(&line[content.len()..]);
+      magics.push(trimmed.to_string());
+    } else {
+      result.push_str(line);
+    }
+  }
+  (result, magics)
+}
+
+/// Restores the magic lines masked by `mask_magics`.
+pub fn unmask_magics(text: &str, magics: &[String]) -> Result<String> {
+  let mut found = vec![false; magics.len()];
+  let mut result = String::with_capacity(text.len());
+  for line in text.split_inclusive('\n') {
+    let content = line.trim_end_matches(['\r', '\n']);
+    let trimmed = content.trim_start();
+    let magic_index = trimmed
+      .strip_prefix(MAGIC_PLACEHOLDER_PREFIX)
+      .and_then(|index| index.parse::<usize>().ok())
+      .filter(|index| *index < magics.len());
+    match magic_index {
+      Some(index) => {
+        found[index] = true;
+        result.push_str(&content[..content.len() - trimmed.len()]);
+        result.push_str(&magics[index]);
+        result.push_str(&line[content.len()..]);
+      }
+      None => result.push_str(line),
+    }
+  }
+  if let Some(index) = found.iter().position(|found| !found) {
+    bail!(
+      "The formatter removed or changed the placeholder for the magic '{}'.",
+      magics[index]
+    );
+  }
+  Ok(result)
+}
+
+struct Node {
+  span: Range<usize>,
+  kind: NodeKind,
+}
+
+enum NodeKind {
+  Object(Vec<(String, Node)>),
+  Array(Vec<Node>),
+  String,
+  Other,
+}
+
+impl Node {
+  fn get(&self, key: &str) -> Option<&Node> {
+    match &self.kind {
+      NodeKind::Object(members) => members
+        .iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, node)| node),
+      _ => None,
+    }
+  }
+
+  fn get_path(&self, keys: &[&str]) -> Option<&Node> {
+    keys.iter().try_fold(self, |node, key| node.get(key))
+  }
+
+  fn as_array(&self) -> Option<&Vec<Node>> {
+    match &self.kind {
+      NodeKind::Array(items) => Some(items),
+      _ => None,
+    }
+  }
+
+  fn decode_string(&self, text: &str) -> Result<String> {
+    match self.kind {
+      NodeKind::String => Ok(serde_json::from_str(&text[self.span.clone()])?),
+      _ => bail!("Expected a string at offset {}.", self.span.start),
+    }
+  }
+}
+
+/// Minimal JSON parser that keeps track of where each value is located.
+struct Scanner<'a> {
+  text: &'a str,
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl Scanner<'_> {
+  fn parse_root(&mut self) -> Result<Node> {
+    let node = self.parse_value()?;
+    self.skip_whitespace();
+    if self.pos != self.bytes.len() {
+      bail!("Unexpected content at offset {}.", self.pos);
+    }
+    Ok(node)
+  }
+
+  fn parse_value(&mut self) -> Result<Node> {
+    self.skip_whitespace();
+    let start = self.pos;
+    let kind = match self.peek() {
+      Some(b'{') => {
+        self.pos += 1;
+        let mut members = Vec::new();
+        if !self.try_consume(b'}') {
+          loop {
+            self.skip_whitespace();
+            let key = self.parse_value()?;
+            let key = key.decode_string(self.text)?;
+            self.expect(b':')?;
+            members.push((key, self.parse_value()?));
+            if self.try_consume(b'}') {
+              break;
+            }
+            self.expect(b',')?;
+          }
+        }
+        NodeKind::Object(members)
+      }
+      Some(b'[') => {
+        self.pos += 1;
+        let mut items = Vec::new();
+        if !self.try_consume(b']') {
+          loop {
+            items.push(self.parse_value()?);
+            if self.try_consume(b']') {
+              break;
+            }
+            self.expect(b',')?;
+          }
+        }
+        NodeKind::Array(items)
+      }
+      Some(b'"') => {
+        self.pos += 1;
+        loop {
+          match self.peek() {
+            Some(b'"') => break,
+            Some(b'\\') => self.pos += 2,
+            Some(_) => self.pos += 1,
+            None => bail!("Unterminated string starting at offset {}.", start),
+          }
+        }
+        self.pos += 1;
+        NodeKind::String
+      }
+      Some(b) if b == b'-' || b.is_ascii_alphanumeric() => {
+        while matches!(self.peek(), Some(b) if b == b'-' || b == b'+' || b == b'.' || b.is_ascii_alphanumeric())
+        {
+          self.pos += 1;
+        }
+        NodeKind::Other
+      }
+      _ => bail!("Unexpected token at offset {}.", start),
+    };
+    Ok(Node {
+      span: start..self.pos,
+      kind,
+    })
+  }
+
+  fn peek(&self) -> Option<u8> {
+    self.bytes.get(self.pos).copied()
+  }
+
+  fn skip_whitespace(&mut self) {
+    while matches!(self.peek(), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+      self.pos += 1;
+    }
+  }
+
+  fn try_consume(&mut self, byte: u8) -> bool {
+    self.skip_whitespace();
+    if self.peek() == Some(byte) {
+      self.pos += 1;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn expect(&mut self, byte: u8) -> Result<()> {
+    if self.try_consume(byte) {
+      Ok(())
+    } else {
+      Err(anyhow!(
+        "Expected '{}' at offset {}.",
+        byte as char,
+        self.pos
+      ))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  const NOTEBOOK: &str = r##"{
+ "cells": [
+  {
+   "cell_type": "markdown",
+   "metadata": {},
+   "source": ["# Title"]
+  },
+  {
+   "cell_type": "code",
+   "execution_count": 1,
+   "metadata": {},
+   "outputs": [{"output_type": "stream", "text": ["hi\n"]}],
+   "source": [
+    "x=1\n",
+    "print( x )"
+   ]
+  },
+  {
+   "cell_type": "code",
+   "metadata": {},
+   "outputs": [],
+   "source": "y = \"é\""
+  }
+ ],
+ "metadata": {
+  "kernelspec": {"language": "python", "name": "python3"}
+ },
+ "nbformat": 4,
+ "nbformat_minor": 5
+}
+"##;
+
+  #[test]
+  fn parses_code_cells() {
+    let notebook = Notebook::parse(NOTEBOOK).unwrap();
+    assert_eq!(notebook.language.as_deref(), Some("python"));
+    let cells = notebook
+      .cells
+      .iter()
+      .map(|cell| (cell.index, cell.source.as_str()))
+      .collect::<Vec<_>>();
+    assert_eq!(cells, vec![(1, "x=1\nprint( x )"), (2, "y = \"é\"")]);
+  }
+
+  #[test]
+  fn replaces_sources_preserving_layout() {
+    let notebook = Notebook::parse(NOTEBOOK).unwrap();
+    let text = replace_sources(
+      NOTEBOOK,
+      &[
+        (&notebook.cells[0], "x = 1\n\nprint(x)".to_string()),
+        (&notebook.cells[1], "y = 'é'".to_string()),
+      ],
+    );
+    assert_eq!(
+      text,
+      NOTEBOOK
+        .replace(
+          "    \"x=1\\n\",\n    \"print( x )\"",
+          "    \"x = 1\\n\",\n    \"\\n\",\n    \"print(x)\""
+        )
+        .replace(r#""source": "y = \"é\"""#, r#""source": "y = 'é'""#)
+    );
+  }
+
+  #[test]
+  fn errors_on_invalid_json() {
+    let err = Notebook::parse("{\"cells\": [}").err().unwrap();
+    assert_eq!(err.to_string(), "Unexpected token at offset 11.");
+  }
+
+  #[test]
+  fn masks_and_unmasks_magics() {
+    let (masked, magics) = mask_magics("%matplotlib inline\nimport os\nif x:\n  !ls -la\n");
+    assert_eq!(
+      masked,
+      concat!(
+        "__dprint_plugin_exec_magic_0\n",
+        "import os\n",
+        "if x:\n",
+        "  __dprint_plugin_exec_magic_1\n",
+      )
+    );
+    assert_eq!(magics, vec!["%matplotlib inline", "!ls -la"]);
+    let formatted = masked.replace("  __dprint", "    __dprint");
+    assert_eq!(
+      unmask_magics(&formatted, &magics).unwrap(),
+      "%matplotlib inline\nimport os\nif x:\n    !ls -la\n"
+    );
+    // a magic that's the only statement of a block stays valid Python
+    let (masked, _) = mask_magics("if x:\n    %time f()\n");
+    assert_eq!(masked, "if x:\n    __dprint_plugin_exec_magic_0\n");
+    let err = unmask_magics("import os\n", &magics).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "The formatter removed or changed the placeholder for the magic '%matplotlib inline'."
+    );
+  }
+}
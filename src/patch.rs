@@ -0,0 +1,336 @@
+use anyhow::Result;
+use anyhow::anyhow;
+use anyhow::bail;
+
+/// A hunk's header, which is `@@ -old_start,old_len +new_start,new_len @@`.
+struct HunkHeader {
+  text: String,
+  old_start: usize,
+  old_len: usize,
+  new_len: usize,
+}
+
+/// Applies a unified diff of the text (ex. the output of `black --diff`) to
+/// the text. Empty output means the text is unchanged.
+pub fn apply_unified_diff(text: &str, diff: &str) -> Result<String> {
+  let lines = text.split_inclusive('\n').collect::<Vec<_>>();
+  let mut result = String::with_capacity(text.len());
+  // index of the next line of the text to copy
+  let mut index = 0;
+  let mut diff_lines = diff.split_inclusive('\n').peekable();
+  while let Some(diff_line) = diff_lines.next() {
+    // lines between hunks are headers (ex. `--- a/file`) and are ignored
+    let Some(header) = parse_hunk_header(diff_line)? else {
+      continue;
+    };
+    // the start is the line before the insertion when the old range is empty
+    let hunk_index = if header.old_len == 0 {
+      header.old_start
+    } else {
+      header.old_start.saturating_sub(1)
+    };
+    if hunk_index < index || hunk_index > lines.len() {
+      bail!(
+        "Hunk '{}' does not apply because it is out of order or beyond the end of the file.",
+        header.text
+      );
+    }
+    for line in &lines[index..hunk_index] {
+      result.push_str(line);
+    }
+    index = hunk_index;
+
+    let mut old_remaining = header.old_len;
+    let mut new_remaining = header.new_len;
+    let mut last_was_addition = false;
+    while old_remaining > 0
+      || new_remaining > 0
+      || diff_lines.peek().is_some_and(|l| l.starts_with('\\'))
+    {
+      let Some(diff_line) = diff_lines.next() else {
+        bail!("Hunk '{}' ended unexpectedly.", header.text);
+      };
+      let content = diff_line.get(1..).unwrap_or("");
+      match diff_line.as_bytes().first() {
+        Some(b'\\') => {
+          // "\ No newline at end of file" applies to the previous line
+          if last_was_addition {
+            let trimmed_len = result.trim_end_matches(['\r', '\n']).len();
+            result.truncate(trimmed_len);
+          }
+        }
+        Some(b'+') => {
+          result.push_str(content);
+          new_remaining = new_remaining.saturating_sub(1);
+          last_was_addition = true;
+        }
+        Some(marker @ (b' ' | b'-' | b'\n' | b'\r')) => {
+          // some tools trim the space of empty context lines
+          let content = if matches!(marker, b'\n' | b'\r') {
+            diff_line
+          } else {
+            content
+          };
+          let Some(line) = lines.get(index) else {
+            bail!(
+              "Hunk '{}' does not apply at line {}: expected '{}', but found the end of the file.",
+              header.text,
+              index + 1,
+              trim_line(content),
+            );
+          };
+          if trim_line(line) != trim_line(content) {
+            bail!(
+              "Hunk '{}' does not apply at line {}: expected '{}', but found '{}'.",
+              header.text,
+              index + 1,
+              trim_line(content),
+              trim_line(line),
+            );
+          }
+          if *marker != b'-' {
+            result.push_str(line);
+            new_remaining = new_remaining.saturating_sub(1);
+          }
+          old_remaining = old_remaining.saturating_sub(1);
+          index += 1;
+          last_was_addition = false;
+        }
+        _ => bail!(
+          "Hunk '{}' has an invalid line: {}",
+          header.text,
+          trim_line(diff_line)
+        ),
+      }
+    }
+  }
+  for line in &lines[index..] {
+    result.push_str(line);
+  }
+  Ok(result)
+}
+
+fn parse_hunk_header(line: &str) -> Result<Option<HunkHeader>> {
+  let Some(ranges) = line.strip_prefix("@@ -") else {
+    return Ok(None);
+  };
+  let text = trim_line(line).to_string();
+  let parse = || {
+    let (old_range, rest) = ranges.split_once(" +")?;
+    let (new_range, _) = rest.split_once(" @@")?;
+    let parse_range = |range: &str| -> Option<(usize, usize)> {
+      match range.split_once(',') {
+        Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+        None => Some((range.parse().ok()?, 1)),
+      }
+    };
+    let (old_start, old_len) = parse_range(old_range)?;
+    let (_, new_len) = parse_range(new_range)?;
+    Some((old_start, old_len, new_len))
+  };
+  let (old_start, old_len, new_len) =
+    parse().ok_or_else(|| anyhow!("Invalid hunk header '{}'.", text))?;
+  Ok(Some(HunkHeader {
+    text,
+    old_start,
+    old_len,
+    new_len,
+  }))
+}
+
+fn trim_line(line: &str) -> &str {
+  line.trim_end_matches(['\r', '\n'])
+}
+
+/// Applies replacements in the XML format output by
+/// `clang-format --output-replacements-xml`, where the offset and length of
+/// each replacement are in bytes. Empty output means the bytes are unchanged.
+pub fn apply_xml_replacements(file_bytes: &[u8], xml: &str) -> Result<Vec<u8>> {
+  let mut replacements = Vec::new();
+  let mut rest = xml;
+  while let Some(start) = rest.find("<replacement ") {
+    rest = &rest[start + "<replacement ".len()..];
+    let tag_end = rest
+      .find('>')
+      .ok_or_else(|| anyhow!("Unclosed replacement element."))?;
+    let attributes = &rest[..tag_end];
+    let offset = parse_attribute(attributes, "offset")?;
+    let length = parse_attribute(attributes, "length")?;
+    rest = &rest[tag_end + 1..];
+    let text = if attributes.trim_end().ends_with('/') {
+      String::new()
+    } else {
+      let end = rest
+        .find("</replacement>")
+        .ok_or_else(|| anyhow!("Unclosed replacement element."))?;
+      let text = unescape_xml(&rest[..end])?;
+      rest = &rest[end..];
+      text
+    };
+    replacements.push((offset, length, text));
+  }
+  replacements.sort_by_key(|(offset, _, _)| *offset);
+
+  let mut result = Vec::with_capacity(file_bytes.len());
+  let mut last_end = 0;
+  for (offset, length, text) in replacements {
+    let end = offset + length;
+    if offset < last_end {
+      bail!(
+        "The replacement at offset {} overlaps another replacement.",
+        offset
+      );
+    }
+    if end > file_bytes.len() {
+      bail!(
+        "The replacement at offset {} with length {} is beyond the end of the file.",
+        offset,
+        length
+      );
+    }
+    result.extend_from_slice(&file_bytes[last_end..offset]);
+    result.extend_from_slice(text.as_bytes());
+    last_end = end;
+  }
+  result.extend_from_slice(&file_bytes[last_end..]);
+  Ok(result)
+}
+
+fn parse_attribute(attributes: &str, name: &str) -> Result<usize> {
+  let value = attributes
+    .split_whitespace()
+    .find_map(|attribute| attribute.strip_prefix(name)?.strip_prefix('='))
+    .map(|value| value.trim_end_matches('/').trim_matches(['\'', '"']))
+    .ok_or_else(|| anyhow!("Replacement is missing the '{}' attribute.", name))?;
+  value
+    .parse()
+    .map_err(|_| anyhow!("Invalid replacement {} '{}'.", name, value))
+}
+
+fn unescape_xml(text: &str) -> Result<String> {
+  let mut result = String::with_capacity(text.len());
+  let mut rest = text;
+  while let Some(start) = rest.find('&') {
+    result.push_str(&rest[..start]);
+    rest = &rest[start..];
+    let end = rest
+      .find(';')
+      .ok_or_else(|| anyhow!("Invalid XML entity in replacement."))?;
+    let entity = &rest[1..end];
+    let c = match entity {
+      "lt" => Some('<'),
+      "gt" => Some('>'),
+      "amp" => Some('&'),
+      "apos" => Some('\''),
+      "quot" => Some('"'),
+      _ => match entity
+        .strip_prefix("#x")
+        .or_else(|| entity.strip_prefix("#X"))
+      {
+        Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+        None => entity
+          .strip_prefix('#')
+          .and_then(|decimal| decimal.parse().ok())
+          .and_then(char::from_u32),
+      },
+    };
+    let c = c.ok_or_else(|| anyhow!("Invalid XML entity '&{};' in replacement.", entity))?;
+    result.push(c);
+    rest = &rest[end + 1..];
+  }
+  result.push_str(rest);
+  Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn applies_unified_diff() {
+    let text = "a\nb\nc\nd\ne\nf\n";
+    let diff = concat!(
+      "--- a/file.txt\n",
+      "+++ b/file.txt\n",
+      "@@ -1,3 +1,3 @@\n",
+      " a\n",
+      "-b\n",
+      "+B\n",
+      " c\n",
+      "@@ -5,0 +6,2 @@\n",
+      "+x\n",
+      "+y\n",
+      "@@ -6 +8 @@\n",
+      "-f\n",
+      "+F\n",
+      "\\ No newline at end of file\n",
+    );
+    assert_eq!(
+      apply_unified_diff(text, diff).unwrap(),
+      "a\nB\nc\nd\ne\nx\ny\nF"
+    );
+    assert_eq!(apply_unified_diff(text, "").unwrap(), text);
+  }
+
+  #[test]
+  fn applies_unified_diff_adding_final_newline() {
+    let diff = concat!(
+      "@@ -1 +1 @@\n",
+      "-a\n",
+      "\\ No newline at end of file\n",
+      "+a\n",
+    );
+    assert_eq!(apply_unified_diff("a", diff).unwrap(), "a\n");
+  }
+
+  #[test]
+  fn errors_when_hunk_does_not_apply() {
+    let diff = "@@ -1,2 +1,2 @@\n a\n-x\n+y\n";
+    assert_eq!(
+      apply_unified_diff("a\nb\n", diff).unwrap_err().to_string(),
+      "Hunk '@@ -1,2 +1,2 @@' does not apply at line 2: expected 'x', but found 'b'."
+    );
+    assert_eq!(
+      apply_unified_diff("a\n", diff).unwrap_err().to_string(),
+      "Hunk '@@ -1,2 +1,2 @@' does not apply at line 2: expected 'x', but found the end of the file."
+    );
+    assert_eq!(
+      apply_unified_diff("a\n", "@@ -1,3 +1,3 @@\n a\n")
+        .unwrap_err()
+        .to_string(),
+      "Hunk '@@ -1,3 +1,3 @@' ended unexpectedly."
+    );
+    assert_eq!(
+      apply_unified_diff("a\n", "@@ -x +1 @@\n")
+        .unwrap_err()
+        .to_string(),
+      "Invalid hunk header '@@ -x +1 @@'."
+    );
+  }
+
+  #[test]
+  fn applies_xml_replacements() {
+    let xml = concat!(
+      "<?xml version='1.0'?>\n",
+      "<replacements xml:space='preserve' incomplete_format='false'>\n",
+      "<replacement offset='8' length='0'>&#10;  </replacement>\n",
+      "<replacement offset='3' length='2'>&lt;&#x3E;</replacement>\n",
+      "<replacement offset='0' length='1'/>\n",
+      "</replacements>\n",
+    );
+    assert_eq!(
+      apply_xml_replacements(b"int  x;{}", xml).unwrap(),
+      b"nt<>x;{\n  }"
+    );
+    assert_eq!(apply_xml_replacements(b"text", "").unwrap(), b"text");
+
+    let xml = "<replacement offset='3' length='5'></replacement>";
+    assert_eq!(
+      apply_xml_replacements(b"text", xml)
+        .unwrap_err()
+        .to_string(),
+      "The replacement at offset 3 with length 5 is beyond the end of the file."
+    );
+  }
+}
@@ -0,0 +1,302 @@
+use anyhow::Error;
+use anyhow::Result;
+use anyhow::anyhow;
+
+use crate::configuration::ProtectConfiguration;
+
+const PLACEHOLDER_PREFIX: &str = "dprint-plugin-exec-ignore-";
+
+/// File text with the parts that shouldn't be formatted swapped out.
+pub struct ProtectedText {
+  /// The text to provide to the command.
+  pub text: String,
+  front_matter: Option<String>,
+  blocks: Vec<IgnoredBlock>,
+}
+
+struct IgnoredBlock {
+  index: usize,
+  text: String,
+  placeholder: String,
+  /// One-based line number of the start marker.
+  start_line: usize,
+}
+
+impl ProtectedText {
+  /// Swaps out the front matter and ignored blocks of the text, returning
+  /// `None` when there is nothing to protect.
+  pub fn new(text: &str, config: &ProtectConfiguration) -> Option<ProtectedText> {
+    let (front_matter, text) = if config.front_matter {
+      split_front_matter(text)
+    } else {
+      (None, text)
+    };
+    let mut blocks = Vec::new();
+    let text = if config.ignore_blocks {
+      let (start_marker, end_marker) = &config.ignore_markers;
+      let line_offset = front_matter.map(|f| f.lines().count()).unwrap_or(0);
+      mask_ignored_blocks(text, start_marker, end_marker, line_offset, &mut blocks)
+    } else {
+      text.to_string()
+    };
+    if front_matter.is_none() && blocks.is_empty() {
+      return None;
+    }
+    Some(ProtectedText {
+      text,
+      front_matter: front_matter.map(ToOwned::to_owned),
+      blocks,
+    })
+  }
+
+  /// Puts the protected parts back into the formatted text.
+  pub fn restore(&self, formatted: &str) -> Result<String> {
+    let mut found = vec![0; self.blocks.len()];
+    let mut result = String::with_capacity(formatted.len());
+    if let Some(front_matter) = &self.front_matter {
+      result.push_str(front_matter);
+    }
+    for line in formatted.split_inclusive('\n') {
+      let content = line.trim_end_matches(['\r', '\n']);
+      match find_placeholder_index(content).and_then(|index| self.blocks.get(index)) {
+        Some(block) => {
+          // the line must only have the placeholder so no text the formatter
+          // moved onto the placeholder's line is lost
+          if content.trim() != block.placeholder {
+            return Err(damaged_placeholder_error(block));
+          }
+          found[block.index] += 1;
+          result.push_str(&block.text);
+          result.push_str(&line[content.len()..]);
+        }
+        None => result.push_str(line),
+      }
+    }
+    if let Some(index) = found.iter().position(|count| *count != 1) {
+      return Err(damaged_placeholder_error(&self.blocks[index]));
+    }
+    Ok(result)
+  }
+}
+
+fn damaged_placeholder_error(block: &IgnoredBlock) -> Error {
+  anyhow!(
+    concat!(
+      "The formatter changed the placeholder of the ignored region starting at line {}. ",
+      "The placeholder must be kept on its own line."
+    ),
+    block.start_line,
+  )
+}
+
+/// Splits off leading YAML (`---`) or TOML (`+++`) front matter.
+fn split_front_matter(text: &str) -> (Option<&str>, &str) {
+  let mut lines = text.split_inclusive('\n');
+  let Some(first_line) = lines.next() else {
+    return (None, text);
+  };
+  let delimiter = first_line.trim_end_matches(['\r', '\n']);
+  if delimiter != "---" && delimiter != "+++" || !first_line.ends_with('\n') {
+    return (None, text);
+  }
+  let mut end = first_line.len();
+  for line in lines {
+    end += line.len();
+    if line.trim_end_matches(['\r', '\n']) == delimiter {
+      return (Some(&text[..end]), &text[end..]);
+    }
+  }
+  (None, text)
+}
+
+/// Replaces each block of lines from a line containing the start marker to a
+/// line containing the end marker with a single placeholder line. The text
+/// surrounding the start marker is kept on the placeholder line so that it
+/// remains a comment in the file's language.
+fn mask_ignored_blocks(
+  text: &str,
+  start_marker: &str,
+  end_marker: &str,
+  line_offset: usize,
+  blocks: &mut Vec<IgnoredBlock>,
+) -> String {
+  let mut result = String::with_capacity(text.len());
+  let mut current: Option<(usize, usize, String)> = None;
+  let mut offset = 0;
+  for (index, line) in text.split_inclusive('\n').enumerate() {
+    let content = line.trim_end_matches(['\r', '\n']);
+    match &current {
+      None => match content.find(start_marker) {
+        Some(marker_pos) => {
+          let placeholder = format!(
+            "{}{}{}{}",
+            &content[..marker_pos],
+            PLACEHOLDER_PREFIX,
+            blocks.len(),
+            &content[marker_pos + start_marker.len()..],
+          );
+          current = Some((offset, line_offset + index + 1, placeholder));
+          // the marker may be closed on the same line
+          if content[marker_pos + start_marker.len()..].contains(end_marker) {
+            let (start, start_line, placeholder) = current.take().unwrap();
+            close_block(
+              text,
+              start,
+              offset + content.len(),
+              start_line,
+              &placeholder,
+              blocks,
+            );
+            result.push_str(&placeholder);
+            result.push_str(&line[content.len()..]);
+          }
+        }
+        None => result.push_str(line),
+      },
+      Some(_) => {
+        if content.contains(end_marker) {
+          let (start, start_line, placeholder) = current.take().unwrap();
+          close_block(
+            text,
+            start,
+            offset + content.len(),
+            start_line,
+            &placeholder,
+            blocks,
+          );
+          result.push_str(&placeholder);
+          result.push_str(&line[content.len()..]);
+        }
+      }
+    }
+    offset += line.len();
+  }
+  // an unclosed block is ignored until the end of the file
+  if let Some((start, start_line, placeholder)) = current {
+    let end = text.trim_end_matches(['\r', '\n']).len().max(start);
+    close_block(text, start, end, start_line, &placeholder, blocks);
+    result.push_str(&placeholder);
+    result.push_str(&text[end..]);
+  }
+  result
+}
+
+fn close_block(
+  text: &str,
+  start: usize,
+  end: usize,
+  start_line: usize,
+  placeholder: &str,
+  blocks: &mut Vec<IgnoredBlock>,
+) {
+  blocks.push(IgnoredBlock {
+    index: blocks.len(),
+    text: text[start..end].to_string(),
+    placeholder: placeholder.trim().to_string(),
+    start_line,
+  });
+}
+
+fn find_placeholder_index(line: &str) -> Option<usize> {
+  let pos = line.find(PLACEHOLDER_PREFIX)?;
+  let digits = &line[pos + PLACEHOLDER_PREFIX.len()..];
+  let len = digits.bytes().take_while(|b| b.is_ascii_digit()).count();
+  digits[..len].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  fn config(front_matter: bool) -> ProtectConfiguration {
+    ProtectConfiguration {
+      ignore_blocks: true,
+      front_matter,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn nothing_to_protect() {
+    assert!(ProtectedText::new("a\nb\n", &config(true)).is_none());
+  }
+
+  #[test]
+  fn masks_and_restores_ignored_blocks() {
+    let text = concat!(
+      "a\n",
+      "  // dprint-ignore-start\n",
+      "  keep   this\n",
+      "  // dprint-ignore-end\n",
+      "b\n",
+      "<!-- dprint-ignore-start --> x <!-- dprint-ignore-end -->\n",
+    );
+    let protected = ProtectedText::new(text, &config(false)).unwrap();
+    assert_eq!(
+      protected.text,
+      concat!(
+        "a\n",
+        "  // dprint-plugin-exec-ignore-0\n",
+        "b\n",
+        "<!-- dprint-plugin-exec-ignore-1 --> x <!-- dprint-ignore-end -->\n",
+      )
+    );
+    let formatted = protected
+      .text
+      .replace("  // dprint", "    // dprint")
+      .replace("b\n", "B\n");
+    assert_eq!(
+      protected.restore(&formatted).unwrap(),
+      text.replace("b\n", "B\n")
+    );
+  }
+
+  #[test]
+  fn unclosed_block_is_ignored_to_end_of_file() {
+    let text = "a\n# dprint-ignore-start\nb\nc\n";
+    let protected = ProtectedText::new(text, &config(false)).unwrap();
+    assert_eq!(protected.text, "a\n# dprint-plugin-exec-ignore-0\n");
+    assert_eq!(protected.restore(&protected.text).unwrap(), text);
+  }
+
+  #[test]
+  fn masks_and_restores_front_matter() {
+    let text = "---\ntitle:   x\n---\n# dprint-ignore-start\na\n# dprint-ignore-end\n";
+    let protected = ProtectedText::new(text, &config(true)).unwrap();
+    assert_eq!(protected.text, "# dprint-plugin-exec-ignore-0\n");
+    assert_eq!(protected.restore(&protected.text).unwrap(), text);
+
+    let protected = ProtectedText::new("+++\na = 1\n+++\ntext", &config(true)).unwrap();
+    assert_eq!(protected.text, "text");
+    assert_eq!(
+      protected.restore("TEXT\n").unwrap(),
+      "+++\na = 1\n+++\nTEXT\n"
+    );
+
+    // not closed, so not front matter
+    assert!(ProtectedText::new("---\na: 1\n", &config(true)).is_none());
+  }
+
+  #[test]
+  fn errors_when_placeholder_damaged() {
+    let text = "a\n// dprint-ignore-start\nb\n// dprint-ignore-end\n";
+    let protected = ProtectedText::new(text, &config(false)).unwrap();
+    let err = protected.restore("a\n").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      concat!(
+        "The formatter changed the placeholder of the ignored region starting at line 2. ",
+        "The placeholder must be kept on its own line."
+      )
+    );
+    let err = protected
+      .restore("// dprint-plugin-exec-ignore-0 // dprint-plugin-exec-ignore-0\n// dprint-plugin-exec-ignore-0\n")
+      .unwrap_err();
+    assert!(err.to_string().contains("line 2"));
+    let err = protected
+      .restore("a // dprint-plugin-exec-ignore-0\n")
+      .unwrap_err();
+    assert!(err.to_string().contains("line 2"));
+  }
+}
@@ -0,0 +1,143 @@
+use anyhow::Result;
+use anyhow::bail;
+use regex::Regex;
+use std::ops::Range;
+
+/// A region of a file delimited by a start and end marker line.
+#[derive(Debug, PartialEq)]
+pub struct Region {
+  /// Byte range of the lines between the marker lines.
+  pub range: Range<usize>,
+  /// One-based line number of the start marker.
+  pub start_line: usize,
+  /// One-based line number of the end marker.
+  pub end_line: usize,
+}
+
+/// Finds the regions in the text where each region is the lines found between
+/// a line matching `start` and the next line matching `end`.
+pub fn find_regions(text: &str, start: &Regex, end: &Regex) -> Result<Vec<Region>> {
+  let mut regions = Vec::new();
+  let mut current: Option<(usize, usize)> = None;
+  let mut offset = 0;
+  for (index, line) in text.split_inclusive('\n').enumerate() {
+    let line_number = index + 1;
+    let line_text = line.trim_end_matches(['\r', '\n']);
+    let line_end = offset + line.len();
+    match current {
+      None => {
+        if start.is_match(line_text) {
+          current = Some((line_number, line_end));
+        }
+      }
+      Some((start_line, content_start)) => {
+        if end.is_match(line_text) {
+          regions.push(Region {
+            range: content_start..offset,
+            start_line,
+            end_line: line_number,
+          });
+          current = None;
+        }
+      }
+    }
+    offset = line_end;
+  }
+  if let Some((start_line, _)) = current {
+    bail!(
+      "The region starting at line {} was never closed by a line matching '{}'.",
+      start_line,
+      end.as_str()
+    );
+  }
+  Ok(regions)
+}
+
+/// Removes the indentation common to all the non-blank lines, returning
+/// the removed indentation and the dedented text.
+pub fn dedent(text: &str) -> (&str, String) {
+  let indent = text
+    .lines()
+    .filter(|line| !line.trim().is_empty())
+    .map(|line| &line[..line.len() - line.trim_start().len()])
+    .reduce(|a, b| {
+      let common = a.bytes().zip(b.bytes()).take_while(|(a, b)| a == b).count();
+      &a[..common]
+    })
+    .unwrap_or("");
+  let text = text
+    .split_inclusive('\n')
+    .map(|line| {
+      line
+        .strip_prefix(indent)
+        .unwrap_or(line.trim_start_matches([' ', '\t']))
+    })
+    .collect();
+  (indent, text)
+}
+
+/// Adds the indentation back to every non-blank line and ensures the
+/// text ends with a newline so the end marker stays on its own line.
+pub fn reindent(text: &str, indent: &str) -> String {
+  let mut result = String::with_capacity(text.len());
+  for line in text.split_inclusive('\n') {
+    if !line.trim().is_empty() {
+      result.push_str(indent);
+    }
+    result.push_str(line);
+  }
+  if !result.is_empty() && !result.ends_with('\n') {
+    result.push('\n');
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn finds_regions() {
+    let text = "a\n-- fmt:sql\nselect 1;\nselect 2;\n-- end\nb\n-- fmt:sql\n-- end\n";
+    let start = Regex::new("-- fmt:sql").unwrap();
+    let end = Regex::new("^-- end$").unwrap();
+    let regions = find_regions(text, &start, &end).unwrap();
+    assert_eq!(
+      regions,
+      vec![
+        Region {
+          range: 13..33,
+          start_line: 2,
+          end_line: 5,
+        },
+        Region {
+          range: 53..53,
+          start_line: 7,
+          end_line: 8,
+        }
+      ]
+    );
+    assert_eq!(&text[regions[0].range.clone()], "select 1;\nselect 2;\n");
+  }
+
+  #[test]
+  fn errors_on_unclosed_region() {
+    let start = Regex::new("start").unwrap();
+    let end = Regex::new("end").unwrap();
+    let err = find_regions("a\nstart\nb\n", &start, &end).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "The region starting at line 2 was never closed by a line matching 'end'."
+    );
+  }
+
+  #[test]
+  fn dedents_and_reindents() {
+    let (indent, text) = dedent("    a\n\n      b\n    c\n");
+    assert_eq!(indent, "    ");
+    assert_eq!(text, "a\n\n  b\nc\n");
+    assert_eq!(reindent(&text, indent), "    a\n\n      b\n    c\n");
+    assert_eq!(reindent("a\n  b", "\t"), "\ta\n\t  b\n");
+  }
+}
@@ -0,0 +1,629 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use handlebars::Context;
+use handlebars::Handlebars;
+use handlebars::RenderErrorReason;
+use serde::Serialize;
+
+use crate::configuration::Configuration;
+
+/// Variables that are the same for every file a command formats.
+#[derive(Clone, Default, Serialize)]
+pub struct CommandTemplateVariables {
+  pub line_width: u32,
+  pub use_tabs: bool,
+  pub indent_width: u8,
+  /// Text of a single indentation level.
+  pub indent_string: String,
+  /// Not available when rendering the cwd itself.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub cwd: Option<String>,
+  pub timeout: u32,
+  /// User defined variables (ex. `{{vars.style}}`).
+  pub vars: BTreeMap<String, String>,
+  /// Environment variables (ex. `{{env.HOME}}`), which only has the ones
+  /// referenced by the templates being rendered (see `with_env_for`).
+  pub env: BTreeMap<String, String>,
+}
+
+impl CommandTemplateVariables {
+  pub fn new(config: &Configuration, vars: BTreeMap<String, String>) -> Self {
+    Self {
+      line_width: config.line_width,
+      use_tabs: config.use_tabs,
+      indent_width: config.indent_width,
+      indent_string: if config.use_tabs {
+        "\t".to_string()
+      } else {
+        " ".repeat(config.indent_width as usize)
+      },
+      cwd: None,
+      timeout: config.timeout,
+      vars,
+      env: BTreeMap::new(),
+    }
+  }
+
+  /// Adds the environment variables the templates reference so that the
+  /// whole environment isn't serialized each time a template is rendered.
+  pub fn with_env_for<'t>(mut self, templates: impl IntoIterator<Item = &'t str>) -> Self {
+    for template in templates {
+      for name in referenced_names(template, "env.") {
+        if !self.env.contains_key(name)
+          && let Some(value) = std::env::var_os(name).and_then(|v| v.into_string().ok())
+        {
+          self.env.insert(name.to_string(), value);
+        }
+      }
+    }
+    self
+  }
+
+  /// Errors when a template or a variable it references contains the
+  /// argument separator, which would otherwise split the argument.
+  pub fn check_separator<'t>(
+    &self,
+    templates: impl IntoIterator<Item = &'t str>,
+  ) -> Result<(), String> {
+    for template in templates {
+      check_separator(&format!("template '{}'", template), template)?;
+      let vars =
+        referenced_names(template, "vars.").map(|name| ("vars", name, self.vars.get(name)));
+      let env = referenced_names(template, "env.").map(|name| ("env", name, self.env.get(name)));
+      for (object, name, value) in vars.chain(env) {
+        if let Some(value) = value {
+          check_separator(&format!("'{}.{}'", object, name), value)?;
+        }
+      }
+      if let Some(cwd) = &self.cwd
+        && template.contains("cwd")
+      {
+        check_separator("'cwd'", cwd)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Gets the names of an object's properties a template references (ex. `HOME`
+/// for `{{env.HOME}}` or `MY-VAR` for `{{env.[MY-VAR]}}` with the `env.` prefix).
+fn referenced_names<'t>(template: &'t str, prefix: &'t str) -> impl Iterator<Item = &'t str> {
+  template
+    .match_indices(prefix)
+    .filter_map(move |(index, prefix)| {
+      let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+      // not part of another name (ex. `vars.myenv.NAME`)
+      if template[..index]
+        .chars()
+        .next_back()
+        .is_some_and(|c| is_ident(c) || c == '.')
+      {
+        return None;
+      }
+      let rest = &template[index + prefix.len()..];
+      let name = match rest.strip_prefix('[') {
+        Some(rest) => &rest[..rest.find(']')?],
+        None => &rest[..rest.find(|c: char| !is_ident(c)).unwrap_or(rest.len())],
+      };
+      (!name.is_empty()).then_some(name)
+    })
+}
+
+/// Variables that can be used in a command's arguments (ex. `{{file_path}}`).
+#[derive(Clone, Serialize)]
+pub struct TemplateVariables<'a> {
+  pub file_path: String,
+  /// File name including the extension.
+  pub file_name: String,
+  /// File name without the extension.
+  pub file_stem: String,
+  /// Extension without the leading period.
+  pub file_ext: String,
+  /// Directory containing the file.
+  pub file_dir: String,
+  /// File path relative to the command's cwd.
+  pub relative_file_path: String,
+  /// File path with symlinks resolved.
+  pub real_path: String,
+  #[serde(flatten)]
+  pub command: &'a CommandTemplateVariables,
+}
+
+impl<'a> TemplateVariables<'a> {
+  /// Creates the variables for the file, where resolving the real path is
+  /// skipped unless it's used since that accesses the file system.
+  pub fn new(
+    file_path: &Path,
+    command_vars: &'a CommandTemplateVariables,
+    resolve_real_path: bool,
+  ) -> Self {
+    let path_text = |path: Option<&Path>| {
+      path
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_default()
+    };
+    let os_str_text = |value: Option<&std::ffi::OsStr>| {
+      value
+        .map(|value| value.to_string_lossy().to_string())
+        .unwrap_or_default()
+    };
+    let cwd = Path::new(command_vars.cwd.as_deref().unwrap_or_default());
+    Self {
+      file_path: path_text(Some(file_path)),
+      file_name: os_str_text(file_path.file_name()),
+      file_stem: os_str_text(file_path.file_stem()),
+      file_ext: os_str_text(file_path.extension()),
+      file_dir: path_text(file_path.parent()),
+      relative_file_path: path_text(Some(file_path.strip_prefix(cwd).unwrap_or(file_path))),
+      // the file might not exist on disk (ex. when formatting stdin)
+      real_path: match resolve_real_path {
+        true => path_text(file_path.canonicalize().ok().as_deref().or(Some(file_path))),
+        false => path_text(Some(file_path)),
+      },
+      command: command_vars,
+    }
+  }
+
+  /// Errors when a file path contains the argument separator (see
+  /// `CommandTemplateVariables::check_separator`).
+  pub fn check_separator(&self) -> Result<(), String> {
+    // the other variables are parts of these paths
+    check_separator("'file_path'", &self.file_path)?;
+    check_separator("'real_path'", &self.real_path)
+  }
+}
+
+/// A command's argument templates, compiled once when the configuration is
+/// resolved so that rendering them for each file is cheap.
+#[derive(Clone, Default)]
+pub struct ArgTemplates {
+  handlebars: Arc<Handlebars<'static>>,
+  /// Source of each template, which is registered by its index.
+  templates: Vec<String>,
+  command_vars: Arc<CommandTemplateVariables>,
+  /// Whether any of the templates use the `real_path` variable.
+  uses_real_path: bool,
+}
+
+impl ArgTemplates {
+  /// Compiles the templates, returning an error message for each one that
+  /// is invalid or references an unknown variable.
+  pub fn compile(
+    templates: &[String],
+    command_vars: CommandTemplateVariables,
+  ) -> Result<Self, Vec<String>> {
+    let mut handlebars = new_handlebars();
+    let command_vars = command_vars.with_env_for(templates.iter().map(String::as_str));
+    let sample_vars = TemplateVariables::new(Path::new(""), &command_vars, false);
+    let mut errors = Vec::new();
+    for (i, template) in templates.iter().enumerate() {
+      let name = i.to_string();
+      let result = handlebars
+        .register_template_string(&name, template)
+        .map_err(|err| format!("Invalid template: {}", err))
+        .and_then(|()| command_vars.check_separator([template.as_str()]))
+        .and_then(|()| render_registered(&handlebars, &name, template, &sample_vars));
+      if let Err(message) = result {
+        errors.push(message);
+      }
+    }
+    if !errors.is_empty() {
+      return Err(errors);
+    }
+    Ok(Self {
+      handlebars: Arc::new(handlebars),
+      templates: templates.to_vec(),
+      command_vars: Arc::new(command_vars),
+      uses_real_path: templates
+        .iter()
+        .any(|template| template.contains("real_path")),
+    })
+  }
+
+  /// Renders the arguments to provide to the command for the file.
+  pub fn render(&self, file_path: &Path) -> Result<Vec<String>, String> {
+    let vars = TemplateVariables::new(file_path, &self.command_vars, self.uses_real_path);
+    vars.check_separator()?;
+    // serialize the variables once for all the templates
+    let context =
+      Context::wraps(&vars).map_err(|err| format!("Cannot render templates: {}", err))?;
+    let mut args = Vec::with_capacity(self.templates.len());
+    for (i, template) in self.templates.iter().enumerate() {
+      let rendered = render_with_context(&self.handlebars, &i.to_string(), template, &context)?;
+      args.extend(expand_arg(&rendered));
+    }
+    Ok(args)
+  }
+}
+
+/// Separates the argv entries an argument expands to (see the `split` helper).
+const ARG_SEPARATOR: char = '\u{1f}';
+
+fn check_separator(name: &str, value: &str) -> Result<(), String> {
+  match value.contains(ARG_SEPARATOR) {
+    true => Err(format!(
+      "Cannot use {} because it contains the character U+001F, which separates arguments.",
+      name
+    )),
+    false => Ok(()),
+  }
+}
+
+/// Creates a strict registry with the helpers available to templates.
+fn new_handlebars() -> Handlebars<'static> {
+  let mut handlebars = Handlebars::new();
+  handlebars.set_strict_mode(true);
+  // the rendered text is used in arguments and environment variables, not HTML
+  handlebars.register_escape_fn(handlebars::no_escape);
+  handlebars.register_helper("lowercase", Box::new(helpers::lowercase));
+  handlebars.register_helper("uppercase", Box::new(helpers::uppercase));
+  handlebars.register_helper("basename", Box::new(helpers::basename));
+  handlebars.register_helper("dirname", Box::new(helpers::dirname));
+  handlebars.register_helper("stem", Box::new(helpers::stem));
+  handlebars.register_helper("extname", Box::new(helpers::extname));
+  handlebars.register_helper("split", Box::new(helpers::split));
+  handlebars.register_helper("read_file", Box::new(helpers::ReadFileHelper));
+  handlebars
+}
+
+/// Splits a rendered argument into the argv entries it expands to,
+/// dropping any that are empty.
+pub fn expand_arg(rendered: &str) -> impl Iterator<Item = String> + '_ {
+  rendered
+    .split(ARG_SEPARATOR)
+    .filter(|arg| !arg.is_empty())
+    .map(ToOwned::to_owned)
+}
+
+/// Renders the template, erroring when it references an unknown variable.
+pub fn render_template(template: &str, data: &impl Serialize) -> Result<String, String> {
+  let mut handlebars = new_handlebars();
+  handlebars
+    .register_template_string("tmp", template)
+    .map_err(|err| format!("Invalid template: {}", err))?;
+  render_registered(&handlebars, "tmp", template, data)
+}
+
+fn render_registered(
+  handlebars: &Handlebars,
+  name: &str,
+  template: &str,
+  data: &impl Serialize,
+) -> Result<String, String> {
+  let context =
+    Context::wraps(data).map_err(|err| format!("Invalid template '{}': {}", template, err))?;
+  render_with_context(handlebars, name, template, &context)
+}
+
+fn render_with_context(
+  handlebars: &Handlebars,
+  name: &str,
+  template: &str,
+  context: &Context,
+) -> Result<String, String> {
+  handlebars
+    .render_with_context(name, context)
+    .map_err(|err| match err.reason() {
+      RenderErrorReason::MissingVariable(Some(name)) => {
+        format!("Unknown variable '{}' in template '{}'.", name, template)
+      }
+      _ => format!("Invalid template '{}': {}", template, err),
+    })
+}
+
+mod helpers {
+  use std::ffi::OsStr;
+  use std::path::Path;
+
+  use handlebars::Context;
+  use handlebars::Handlebars;
+  use handlebars::Helper;
+  use handlebars::HelperDef;
+  use handlebars::JsonValue;
+  use handlebars::RenderContext;
+  use handlebars::RenderError;
+  use handlebars::RenderErrorReason;
+  use handlebars::ScopedJson;
+  use handlebars::handlebars_helper;
+
+  use super::ARG_SEPARATOR;
+  use super::check_separator;
+
+  handlebars_helper!(lowercase: |value: str| value.to_lowercase());
+  handlebars_helper!(uppercase: |value: str| value.to_uppercase());
+  handlebars_helper!(basename: |path: str| path_part(path, Path::file_name));
+  handlebars_helper!(stem: |path: str| path_part(path, Path::file_stem));
+  handlebars_helper!(extname: |path: str| path_part(path, Path::extension));
+  handlebars_helper!(dirname: |path: str| {
+    Path::new(path)
+      .parent()
+      .map(|dir| dir.to_string_lossy().to_string())
+      .unwrap_or_default()
+  });
+  // expands to one argv entry per item, splitting on whitespace by default
+  handlebars_helper!(split: |value: str, {sep: str = ""}| {
+    let items = if sep.is_empty() {
+      value.split_whitespace().collect::<Vec<_>>()
+    } else {
+      value.split(sep).collect::<Vec<_>>()
+    };
+    items.join(&ARG_SEPARATOR.to_string())
+  });
+
+  /// Reads the trimmed text of a file relative to the `cwd` variable
+  /// (ex. `{{read_file "rust-toolchain"}}`).
+  pub struct ReadFileHelper;
+
+  impl HelperDef for ReadFileHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+      &self,
+      h: &Helper<'rc>,
+      _: &'reg Handlebars<'reg>,
+      ctx: &'rc Context,
+      _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+      let path = h
+        .param(0)
+        .and_then(|param| param.value().as_str())
+        .ok_or(RenderErrorReason::ParamNotFoundForIndex("read_file", 0))?;
+      let path = match ctx.data().get("cwd").and_then(|cwd| cwd.as_str()) {
+        Some(cwd) => Path::new(cwd).join(path),
+        None => Path::new(path).to_path_buf(),
+      };
+      let text = std::fs::read_to_string(&path).map_err(|err| {
+        RenderErrorReason::NestedError(
+          format!("Unable to read file '{}': {}", path.display(), err).into(),
+        )
+      })?;
+      let name = format!("the text of file '{}'", path.display());
+      check_separator(&name, &text).map_err(RenderErrorReason::Other)?;
+      Ok(ScopedJson::Derived(JsonValue::from(text.trim())))
+    }
+  }
+
+  fn path_part(path: &str, get_part: impl Fn(&Path) -> Option<&OsStr>) -> String {
+    get_part(Path::new(path))
+      .map(|part| part.to_string_lossy().to_string())
+      .unwrap_or_default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pretty_assertions::assert_eq;
+  use serde_json::json;
+
+  #[test]
+  fn file_path_variables() {
+    let config = Configuration::resolve(
+      serde_json::from_value(json!({
+        "indentWidth": 4,
+        "vars": { "style": "google" },
+        "commands": [{
+          "cwd": "/project",
+          "exts": ["ts"],
+          "command": "formatter",
+        }],
+      }))
+      .unwrap(),
+      &Default::default(),
+    )
+    .config;
+    let mut command_vars = CommandTemplateVariables::new(&config, config.commands[0].vars.clone());
+    command_vars.cwd = Some("/project".to_string());
+    let vars = TemplateVariables::new(Path::new("/project/src/mod.test.ts"), &command_vars, true);
+    assert_eq!(vars.file_name, "mod.test.ts");
+    assert_eq!(vars.file_stem, "mod.test");
+    assert_eq!(vars.file_ext, "ts");
+    assert_eq!(vars.file_dir, "/project/src");
+    assert_eq!(vars.relative_file_path, "src/mod.test.ts");
+    assert_eq!(vars.real_path, "/project/src/mod.test.ts");
+    assert_eq!(vars.command.indent_string, "    ");
+    assert_eq!(vars.command.cwd.as_deref(), Some("/project"));
+    assert_eq!(
+      render_template("{{cwd}}/{{file_stem}}.{{vars.style}}", &vars).unwrap(),
+      "/project/mod.test.google"
+    );
+  }
+
+  #[test]
+  fn file_path_variables_without_extension_or_parent() {
+    let command_vars = CommandTemplateVariables {
+      cwd: Some("/project".to_string()),
+      ..Default::default()
+    };
+    let vars = TemplateVariables::new(Path::new("/project/Makefile"), &command_vars, true);
+    assert_eq!(vars.file_name, "Makefile");
+    assert_eq!(vars.file_stem, "Makefile");
+    assert_eq!(vars.file_ext, "");
+    assert_eq!(vars.file_dir, "/project");
+    assert_eq!(vars.relative_file_path, "Makefile");
+    // the file doesn't exist, so this is the file path
+    assert_eq!(vars.real_path, "/project/Makefile");
+
+    let vars = TemplateVariables::new(Path::new("dprint-exec-missing.ts"), &command_vars, true);
+    assert_eq!(vars.file_name, "dprint-exec-missing.ts");
+    assert_eq!(vars.file_stem, "dprint-exec-missing");
+    assert_eq!(vars.file_ext, "ts");
+    assert_eq!(vars.file_dir, "");
+    assert_eq!(vars.relative_file_path, "dprint-exec-missing.ts");
+    assert_eq!(vars.real_path, "dprint-exec-missing.ts");
+
+    let templates = ["{{file_dir}}", "{{file_ext}}", "{{real_path}}"]
+      .iter()
+      .map(|t| t.to_string())
+      .collect::<Vec<_>>();
+    let templates = ArgTemplates::compile(&templates, command_vars).unwrap();
+    // empty arguments are dropped
+    assert_eq!(
+      templates.render(Path::new("Makefile")).unwrap(),
+      vec!["Makefile"]
+    );
+  }
+
+  #[test]
+  fn compiles_and_renders_arg_templates() {
+    let command_vars = CommandTemplateVariables {
+      cwd: Some("/project".to_string()),
+      vars: BTreeMap::from([("style".to_string(), "google".to_string())]),
+      ..Default::default()
+    };
+    let compile = |templates: &[&str]| {
+      let templates = templates.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+      ArgTemplates::compile(&templates, command_vars.clone())
+    };
+    let templates = compile(&[
+      "--width={{line_width}}",
+      "{{#if use_tabs}}--tabs{{/if}}",
+      "--style={{vars.style}}",
+      "{{relative_file_path}}",
+    ])
+    .unwrap();
+    assert_eq!(
+      templates.render(Path::new("/project/a.ts")).unwrap(),
+      vec!["--width=0", "--style=google", "a.ts"]
+    );
+    assert_eq!(
+      templates.render(Path::new("/project/b.ts")).unwrap(),
+      vec!["--width=0", "--style=google", "b.ts"]
+    );
+
+    let errors = compile(&["--{{unknown}}", "{{vars.other}}", "{{file_path"])
+      .err()
+      .unwrap();
+    assert_eq!(errors.len(), 3);
+    assert_eq!(
+      errors[0],
+      "Unknown variable 'unknown' in template '--{{unknown}}'."
+    );
+    assert_eq!(
+      errors[1],
+      "Unknown variable 'vars.other' in template '{{vars.other}}'."
+    );
+    assert!(errors[2].starts_with("Invalid template: "));
+  }
+
+  #[test]
+  fn expands_args() {
+    let vars = CommandTemplateVariables {
+      vars: BTreeMap::from([
+        ("flags".to_string(), " --a  --b ".to_string()),
+        ("paths".to_string(), "x,y".to_string()),
+      ]),
+      ..Default::default()
+    };
+    let expand =
+      |template: &str| expand_arg(&render_template(template, &vars).unwrap()).collect::<Vec<_>>();
+    assert_eq!(
+      expand("{{#if use_tabs}}--use-tabs{{/if}}"),
+      Vec::<String>::new()
+    );
+    assert_eq!(expand("{{split vars.flags}}"), vec!["--a", "--b"]);
+    assert_eq!(
+      expand("--include={{split vars.paths sep=\",\"}}"),
+      vec!["--include=x", "y"]
+    );
+  }
+
+  #[test]
+  fn rejects_values_with_arg_separator() {
+    let command_vars = CommandTemplateVariables {
+      vars: BTreeMap::from([("flags".to_string(), "--a\u{1f}--b".to_string())]),
+      ..Default::default()
+    };
+    let compile =
+      |template: &str| ArgTemplates::compile(&[template.to_string()], command_vars.clone());
+    assert_eq!(
+      compile("{{vars.flags}}").err().unwrap(),
+      vec![
+        "Cannot use 'vars.flags' because it contains the character U+001F, which separates arguments."
+      ]
+    );
+    // unused variables are fine
+    let templates = compile("{{file_path}}").unwrap();
+    assert_eq!(templates.render(Path::new("a.ts")).unwrap(), vec!["a.ts"]);
+    assert_eq!(
+      templates.render(Path::new("a\u{1f}b.ts")).unwrap_err(),
+      "Cannot use 'file_path' because it contains the character U+001F, which separates arguments."
+    );
+  }
+
+  #[test]
+  fn helpers() {
+    let vars = CommandTemplateVariables {
+      vars: BTreeMap::from([("path".to_string(), "/src/Mod.Test.ts".to_string())]),
+      ..Default::default()
+    };
+    let render = |template: &str| render_template(template, &vars).unwrap();
+    assert_eq!(render("{{lowercase vars.path}}"), "/src/mod.test.ts");
+    assert_eq!(render("{{uppercase (basename vars.path)}}"), "MOD.TEST.TS");
+    assert_eq!(render("{{dirname vars.path}}"), "/src");
+    assert_eq!(render("{{stem vars.path}}"), "Mod.Test");
+    assert_eq!(render("{{extname vars.path}}"), "ts");
+    assert_eq!(
+      render("{{read_file \"tests/resources/one-line.txt\"}}"),
+      std::fs::read_to_string("tests/resources/one-line.txt")
+        .unwrap()
+        .trim()
+    );
+    assert!(
+      render_template("{{read_file \"missing.txt\"}}", &vars)
+        .unwrap_err()
+        .contains("Unable to read file 'missing.txt'")
+    );
+  }
+
+  #[test]
+  fn only_has_referenced_env_vars() {
+    let path = std::env::var("PATH").unwrap();
+    let templates = ["--path={{env.PATH}}", "{{env.[PATH]}}:myenv.HOME"]
+      .iter()
+      .map(|t| t.to_string())
+      .collect::<Vec<_>>();
+    let templates = ArgTemplates::compile(&templates, Default::default()).unwrap();
+    assert_eq!(
+      templates.command_vars.env,
+      BTreeMap::from([("PATH".to_string(), path.clone())])
+    );
+    assert_eq!(
+      templates.render(Path::new("a.ts")).unwrap(),
+      vec![format!("--path={}", path), format!("{}:myenv.HOME", path)]
+    );
+
+    let errors = ArgTemplates::compile(
+      &["{{env.DPRINT_EXEC_UNSET_VAR}}".to_string()],
+      Default::default(),
+    )
+    .err()
+    .unwrap();
+    assert_eq!(
+      errors,
+      vec![
+        "Unknown variable 'env.DPRINT_EXEC_UNSET_VAR' in template '{{env.DPRINT_EXEC_UNSET_VAR}}'."
+      ]
+    );
+  }
+
+  #[test]
+  fn renders_env_and_vars() {
+    let mut command_vars = CommandTemplateVariables {
+      vars: BTreeMap::from([("tool".to_string(), "black".to_string())]),
+      env: BTreeMap::from([("HOME".to_string(), "/home/user".to_string())]),
+      ..Default::default()
+    };
+    assert_eq!(
+      render_template("{{env.HOME}}/bin/{{vars.tool}}", &command_vars).unwrap(),
+      "/home/user/bin/black"
+    );
+    // the cwd can't be used until it's resolved
+    assert!(render_template("{{cwd}}", &command_vars).is_err());
+    command_vars.cwd = Some("/project".to_string());
+    assert_eq!(
+      render_template("{{cwd}}", &command_vars).unwrap(),
+      "/project"
+    );
+  }
+}
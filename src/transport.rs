@@ -0,0 +1,207 @@
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use anyhow::anyhow;
+use anyhow::bail;
+use serde::Serialize;
+
+/// Status byte of a response with the formatted bytes.
+const STATUS_OK: u8 = 0;
+/// Status byte of a response with an error message.
+const STATUS_ERROR: u8 = 1;
+
+/// Information sent along with the file bytes.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SocketRequestMetadata {
+  pub file_path: PathBuf,
+  pub executable: String,
+  pub args: Vec<String>,
+  pub cwd: PathBuf,
+  pub line_width: u32,
+  pub indent_width: u8,
+  pub use_tabs: bool,
+}
+
+pub enum SocketResponse {
+  /// Nothing is listening on the socket.
+  Unavailable,
+  Formatted(Vec<u8>),
+}
+
+/// Sends the file bytes to the service listening on the Unix socket.
+///
+/// A request is a frame with the JSON metadata followed by a frame with the
+/// file bytes, where a frame is a 32-bit big-endian length followed by that
+/// many bytes. The response is a status byte (0 for success or 1 for an
+/// error) followed by a frame with the formatted bytes or a UTF-8 error
+/// message. Each request uses its own connection.
+///
+/// This blocks, so the timeout is also set on the socket to ensure it
+/// doesn't block forever.
+pub fn send_socket_request(
+  socket_path: &Path,
+  metadata: &SocketRequestMetadata,
+  file_bytes: &[u8],
+  timeout: Duration,
+) -> Result<SocketResponse> {
+  #[cfg(unix)]
+  {
+    let mut stream = match std::os::unix::net::UnixStream::connect(socket_path) {
+      Ok(stream) => stream,
+      Err(err)
+        if matches!(
+          err.kind(),
+          std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+        ) =>
+      {
+        return Ok(SocketResponse::Unavailable);
+      }
+      Err(err) => bail!(
+        "Cannot connect to the socket at '{}': {}",
+        socket_path.display(),
+        err
+      ),
+    };
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write_request(&mut stream, metadata, file_bytes)
+      .and_then(|_| stream.shutdown(std::net::Shutdown::Write))
+      .map_err(|err| anyhow!("Cannot write to the socket: {}", err))?;
+    read_response(&mut stream).map(SocketResponse::Formatted)
+  }
+  #[cfg(not(unix))]
+  {
+    // Unix sockets aren't supported, so this always falls back to the command
+    let _ = (socket_path, metadata, file_bytes, timeout);
+    Ok(SocketResponse::Unavailable)
+  }
+}
+
+fn write_request(
+  writer: &mut impl Write,
+  metadata: &SocketRequestMetadata,
+  file_bytes: &[u8],
+) -> std::io::Result<()> {
+  write_frame(writer, &serde_json::to_vec(metadata)?)?;
+  write_frame(writer, file_bytes)?;
+  writer.flush()
+}
+
+fn write_frame(writer: &mut impl Write, bytes: &[u8]) -> std::io::Result<()> {
+  let len = u32::try_from(bytes.len()).map_err(std::io::Error::other)?;
+  writer.write_all(&len.to_be_bytes())?;
+  writer.write_all(bytes)
+}
+
+fn read_response(reader: &mut impl Read) -> Result<Vec<u8>> {
+  let read = |reader: &mut dyn Read| -> std::io::Result<(u8, Vec<u8>)> {
+    let mut status = [0; 1];
+    reader.read_exact(&mut status)?;
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok((status[0], bytes))
+  };
+  let (status, bytes) = read(reader).map_err(|err| anyhow!("Invalid response: {}", err))?;
+  match status {
+    STATUS_OK => Ok(bytes),
+    STATUS_ERROR => bail!("{}", String::from_utf8_lossy(&bytes)),
+    _ => bail!("Invalid response status {}.", status),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pretty_assertions::assert_eq;
+
+  fn metadata() -> SocketRequestMetadata {
+    SocketRequestMetadata {
+      file_path: PathBuf::from("/dir/file.txt"),
+      executable: "formatter".to_string(),
+      args: vec!["--check".to_string()],
+      cwd: PathBuf::from("/dir"),
+      line_width: 80,
+      indent_width: 2,
+      use_tabs: false,
+    }
+  }
+
+  #[test]
+  fn frames_request() {
+    let mut bytes = Vec::new();
+    write_request(&mut bytes, &metadata(), b"text").unwrap();
+    let json = concat!(
+      r#"{"filePath":"/dir/file.txt","executable":"formatter","args":["--check"],"#,
+      r#""cwd":"/dir","lineWidth":80,"indentWidth":2,"useTabs":false}"#,
+    );
+    let mut expected = (json.len() as u32).to_be_bytes().to_vec();
+    expected.extend(json.as_bytes());
+    expected.extend(4u32.to_be_bytes());
+    expected.extend(b"text");
+    assert_eq!(bytes, expected);
+  }
+
+  #[test]
+  fn reads_response() {
+    let response = |status: u8, bytes: &[u8]| {
+      let mut response = vec![status];
+      response.extend((bytes.len() as u32).to_be_bytes());
+      response.extend(bytes);
+      response
+    };
+    let result = read_response(&mut response(STATUS_OK, b"formatted").as_slice());
+    assert_eq!(result.unwrap(), b"formatted");
+    let result = read_response(&mut response(STATUS_ERROR, b"syntax error").as_slice());
+    assert_eq!(result.unwrap_err().to_string(), "syntax error");
+    let result = read_response(&mut response(2, b"").as_slice());
+    assert_eq!(
+      result.unwrap_err().to_string(),
+      "Invalid response status 2."
+    );
+    let result = read_response(&mut [STATUS_OK, 0, 0].as_slice());
+    assert!(
+      result
+        .unwrap_err()
+        .to_string()
+        .starts_with("Invalid response")
+    );
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn sends_request_to_socket() {
+    let dir = std::env::temp_dir().join(format!("dprint-exec-socket-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket_path = dir.join("formatter.sock");
+    let timeout = Duration::from_secs(10);
+    let result = send_socket_request(&socket_path, &metadata(), b"text", timeout).unwrap();
+    assert!(matches!(result, SocketResponse::Unavailable));
+
+    let listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+    let server = std::thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut request = Vec::new();
+      stream.read_to_end(&mut request).unwrap();
+      // uppercase the file bytes, which are after the metadata frame
+      let metadata_len = u32::from_be_bytes(request[..4].try_into().unwrap()) as usize;
+      let file_bytes = &request[4 + metadata_len + 4..];
+      stream.write_all(&[STATUS_OK]).unwrap();
+      write_frame(&mut stream, &file_bytes.to_ascii_uppercase()).unwrap();
+    });
+    let result = send_socket_request(&socket_path, &metadata(), b"text", timeout).unwrap();
+    server.join().unwrap();
+    match result {
+      SocketResponse::Formatted(bytes) => assert_eq!(bytes, b"TEXT"),
+      SocketResponse::Unavailable => unreachable!(),
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
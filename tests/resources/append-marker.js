@@ -1,5 +1,7 @@
-// appends a single byte to the file path given as the first argument.
+// appends a single byte to the file path given as the first argument and
+// exits with the optional exit code given as the second argument.
 // used by tests to count how many times a setup command runs.
 import { appendFileSync } from "node:fs";
 
 appendFileSync(Deno.args[0], "x");
+Deno.exit(Number(Deno.args[1] ?? 0));
@@ -0,0 +1,14 @@
+// a minimal linter used by tests. reports each line of stdin containing the
+// first argument on stdout and exits with code 1 when there are any
+import process from "node:process";
+
+let text = "";
+process.stdin.on("data", (chunk) => text += chunk);
+process.stdin.on("end", () => {
+  const findings = text.split("\n")
+    .map((line, index) => line.includes(Deno.args[0]) ? `line ${index + 1}: found '${Deno.args[0]}'` : undefined)
+    .filter((finding) => finding != null);
+  // the output of checks is ignored when they succeed
+  const output = findings.length === 0 ? "ignored\n" : findings.join("\n") + "\n";
+  process.stdout.write(output, () => Deno.exit(findings.length === 0 ? 0 : 1));
+});
@@ -0,0 +1,88 @@
+// a minimal language server used by tests. formatting replaces each "foo"
+// with "bar", text containing "fail" responds with an error and text
+// containing "crash" exits the server. appends "x" to the file path given as
+// the first argument each time the server is initialized and "e" when it's
+// told to exit.
+import { appendFileSync } from "node:fs";
+import process from "node:process";
+
+const documents = new Map();
+let buffer = Buffer.alloc(0);
+
+process.stdin.on("data", (chunk) => {
+  buffer = Buffer.concat([buffer, chunk]);
+  while (true) {
+    const headerEnd = buffer.indexOf("\r\n\r\n");
+    if (headerEnd === -1) return;
+    const length = Number(/Content-Length: (\d+)/i.exec(buffer.subarray(0, headerEnd).toString())[1]);
+    if (buffer.length < headerEnd + 4 + length) return;
+    const body = buffer.subarray(headerEnd + 4, headerEnd + 4 + length).toString();
+    buffer = buffer.subarray(headerEnd + 4 + length);
+    handle(JSON.parse(body));
+  }
+});
+
+function send(message) {
+  const body = Buffer.from(JSON.stringify({ jsonrpc: "2.0", ...message }));
+  process.stdout.write(`Content-Length: ${body.length}\r\n\r\n`);
+  process.stdout.write(body);
+}
+
+function handle(message) {
+  switch (message.method) {
+    case "initialize":
+      if (Deno.args[0]) appendFileSync(Deno.args[0], "x");
+      send({
+        id: message.id,
+        result: { capabilities: { documentFormattingProvider: true, documentRangeFormattingProvider: true } },
+      });
+      break;
+    case "initialized":
+      // servers may request their configuration from the client
+      send({ id: "config", method: "workspace/configuration", params: { items: [{}, {}] } });
+      break;
+    case "textDocument/didOpen":
+      documents.set(message.params.textDocument.uri, message.params.textDocument.text);
+      break;
+    case "textDocument/didClose":
+      documents.delete(message.params.textDocument.uri);
+      break;
+    case "textDocument/formatting":
+    case "textDocument/rangeFormatting": {
+      const text = documents.get(message.params.textDocument.uri);
+      if (text.includes("crash")) process.exit(1);
+      if (text.includes("fail")) {
+        send({ id: message.id, error: { code: -32603, message: "cannot format" } });
+        break;
+      }
+      const range = message.params.range;
+      const start = range ? toIndex(text, range.start) : 0;
+      const end = range ? toIndex(text, range.end) : text.length;
+      const edits = [];
+      for (let index = text.indexOf("foo", start); index !== -1 && index + 3 <= end; index = text.indexOf("foo", index + 3)) {
+        edits.push({ range: { start: toPosition(text, index), end: toPosition(text, index + 3) }, newText: "bar" });
+      }
+      send({ id: message.id, result: edits });
+      break;
+    }
+    case "exit":
+      if (Deno.args[0]) appendFileSync(Deno.args[0], "e");
+      process.exit(0);
+    default:
+      if (message.id != null && message.method) send({ id: message.id, result: null });
+  }
+}
+
+// javascript strings are indexed by UTF-16 code units like LSP positions
+function toPosition(text, index) {
+  const before = text.slice(0, index);
+  const lines = before.split("\n");
+  return { line: lines.length - 1, character: lines[lines.length - 1].length };
+}
+
+function toIndex(text, position) {
+  const lines = text.split("\n");
+  let index = 0;
+  for (let i = 0; i < position.line; i++) index += lines[i].length + 1;
+  return index + position.character;
+}
@@ -0,0 +1,7 @@
+// writes the file at the path given as the first argument to stdout and
+// exits with the optional exit code given as the second argument.
+// used by tests to simulate commands that output patches.
+import { readFileSync } from "node:fs";
+import process from "node:process";
+
+process.stdout.write(readFileSync(Deno.args[0]), () => Deno.exit(Number(Deno.args[1] ?? 0)));
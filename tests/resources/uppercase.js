@@ -0,0 +1,6 @@
+// outputs the uppercased text provided via stdin
+import process from "node:process";
+
+let text = "";
+process.stdin.on("data", (chunk) => text += chunk);
+process.stdin.on("end", () => process.stdout.write(text.toUpperCase()));